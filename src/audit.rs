@@ -0,0 +1,136 @@
+use crate::*;
+use near_sdk::{
+    near,
+    serde_json::{self, Value},
+};
+
+// Entries returned by each call of `get_audit_log`
+const MAX_AUDIT_PAGE: u32 = 100;
+
+#[near(serializers=[borsh])]
+pub struct AdminAction {
+    pub caller: AccountId,
+    // Block timestamp (ms)
+    pub timestamp: u64,
+    pub method: String,
+    // JSON of the values before and after the action
+    pub old: String,
+    pub new: String,
+}
+
+#[near(serializers=[json])]
+pub struct AdminActionView {
+    pub id: u32,
+    pub caller: AccountId,
+    pub timestamp: u64,
+    pub method: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[near(serializers=[borsh])]
+pub struct AuditLog {
+    pub entries: Vector<AdminAction>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self {
+            entries: Vector::new(StorageKey::AuditLog),
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    // Entries with `from <= id < until`, oldest first and at most
+    // `MAX_AUDIT_PAGE` per call
+    pub fn get_audit_log(&self, from: u32, until: u32) -> Vec<AdminActionView> {
+        let until = until
+            .min(self.audit.entries.len())
+            .min(from.saturating_add(MAX_AUDIT_PAGE));
+
+        (from..until)
+            .map(|id| {
+                let action = &self.audit.entries[id];
+
+                AdminActionView {
+                    id,
+                    caller: action.caller.clone(),
+                    timestamp: action.timestamp,
+                    method: action.method.clone(),
+                    old: serde_json::from_str(&action.old).unwrap_or(Value::Null),
+                    new: serde_json::from_str(&action.new).unwrap_or(Value::Null),
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_audit_log_length(&self) -> u32 {
+        self.audit.entries.len()
+    }
+
+    // Every admin method records what it changed, the transaction alone
+    // does not tell which values were there before
+    pub(crate) fn record_admin_action(&mut self, method: &str, old: Value, new: Value) {
+        self.audit.entries.push(AdminAction {
+            caller: env::predecessor_account_id(),
+            timestamp: env::block_timestamp_ms(),
+            method: method.to_string(),
+            old: old.to_string(),
+            new: new.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::serde_json::json;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_audit_log() {
+        set_context(&"contract".parse().unwrap(), 1);
+        let mut contract = Contract::for_tests();
+
+        contract.set_epochs_wait(6);
+        contract.grant_role(Role::Pauser, accounts(1));
+
+        set_context(&accounts(1), 2);
+        contract.emergency_start();
+
+        assert_eq!(contract.get_audit_log_length(), 3);
+
+        let log = contract.get_audit_log(0, 10);
+        assert_eq!(log[0].method, "set_epochs_wait");
+        assert_eq!(log[0].old, json!({ "epochs_wait": 4 }));
+        assert_eq!(log[0].new, json!({ "epochs_wait": 6 }));
+        assert_eq!(log[1].method, "grant_role");
+
+        assert_eq!(log[2].id, 2);
+        assert_eq!(log[2].caller, accounts(1));
+        assert_eq!(log[2].timestamp, 2);
+        assert_eq!(log[2].old["paused"]["deposit"], false);
+        assert_eq!(log[2].new["paused"]["deposit"], true);
+
+        assert_eq!(contract.get_audit_log(1, 2).len(), 1);
+
+        // Long ranges are cut to a page
+        set_context(&"contract".parse().unwrap(), 3);
+        for _ in 0..MAX_AUDIT_PAGE {
+            contract.set_epochs_wait(5);
+        }
+        assert_eq!(contract.get_audit_log(0, u32::MAX).len(), 100);
+        assert_eq!(contract.get_audit_log(50, u32::MAX).len(), 53);
+    }
+
+    fn set_context(account: &AccountId, timestamp_ms: u64) {
+        testing_env!(context(account)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
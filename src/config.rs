@@ -0,0 +1,363 @@
+use crate::*;
+use near_sdk::{
+    near, require,
+    serde_json::{json, Map, Value},
+};
+
+// Staking pools release unstaked funds after 4 epochs, withdraw turns
+// retry until they do, but waiting weeks only delays the users
+const MIN_EPOCHS_WAIT: u64 = 1;
+const MAX_EPOCHS_WAIT: u64 = 16;
+
+// The fee is a percentage of the prize
+const MAX_POOL_FEE: u8 = 100;
+
+// Longer delays would block urgent changes (30 days in ms)
+const MAX_TIMELOCK_DELAY: u64 = 2_592_000_000;
+
+// Every field is optional, only the ones present change
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Default)]
+pub struct ConfigPatch {
+    pub min_to_raffle: Option<NearToken>,
+    pub max_to_raffle: Option<NearToken>,
+    pub min_deposit: Option<NearToken>,
+    pub max_deposit: Option<NearToken>,
+    pub epochs_wait: Option<u64>,
+    pub time_between_raffles: Option<U64>,
+    pub pool_fee: Option<u8>,
+    pub batch_deposits: Option<bool>,
+    pub ping_before_prize: Option<bool>,
+    pub prize_gas: Option<PrizeGas>,
+    pub health_policy: Option<HealthPolicy>,
+    // Delay (ms) of the timelocked changes
+    pub timelock_delay: Option<U64>,
+}
+
+#[near]
+impl Contract {
+    // Applies the whole patch or nothing. Changes users need to hear
+    // about in advance go through `propose_change` instead
+    pub fn update_config(&mut self, patch: ConfigPatch) {
+        self.change_config("update_config", patch);
+    }
+
+    // Shared by `update_config` and the setters, the audit log keeps the method
+    pub(crate) fn change_config(&mut self, method: &str, patch: ConfigPatch) {
+        self.require_patch_roles(&patch);
+        require!(
+            !patch.is_sensitive(&self.config),
+            "Sensitive changes are timelocked, use propose_change"
+        );
+
+        let (old, new) = self.apply_config(patch);
+        self.record_admin_action(method, old, new);
+    }
+
+    // The fee needs the FeeManager role, the delay the Owner role
+    // and every other field the Operator role
+    pub(crate) fn require_patch_roles(&self, patch: &ConfigPatch) {
+        // Every change needs a role, an empty patch would need none
+        require!(!patch.is_empty(), "The patch changes nothing");
+        if patch.pool_fee.is_some() {
+            self.require_role(&[Role::FeeManager]);
+        }
+        if patch.timelock_delay.is_some() {
+            self.require_role(&[Role::Owner]);
+        }
+        if patch.has_parameters() {
+            self.require_role(&[Role::Operator]);
+        }
+    }
+
+    // Proposals are checked against the current config, their execution checks again
+    pub(crate) fn validate_patch(&mut self, patch: ConfigPatch) {
+        let (config, pool_fee) = (self.config.clone(), self.pool.pool_fee);
+        self.patch_config(patch);
+        self.validate_config();
+        self.config = config;
+        self.pool.pool_fee = pool_fee;
+    }
+
+    // Returns the old and new values of the fields that changed
+    pub(crate) fn apply_config(&mut self, patch: ConfigPatch) -> (Value, Value) {
+        let old = self.config_values();
+
+        // Panicking reverts the changes
+        self.patch_config(patch);
+        self.validate_config();
+
+        let (old, new): (Map<String, Value>, Map<String, Value>) = self
+            .config_values()
+            .into_iter()
+            .filter(|(field, value)| old.get(field) != Some(value))
+            .map(|(field, value)| ((field.clone(), old[&field].clone()), (field, value)))
+            .unzip();
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "config_changed",
+            "data": {
+                "old": old,
+                "new": new,
+                "by": env::predecessor_account_id(),
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        (Value::Object(old), Value::Object(new))
+    }
+
+    fn patch_config(&mut self, patch: ConfigPatch) {
+        let config = &mut self.config;
+        if let Some(value) = patch.min_to_raffle {
+            config.min_to_raffle = value;
+        }
+        if let Some(value) = patch.max_to_raffle {
+            config.max_to_raffle = value;
+        }
+        if let Some(value) = patch.min_deposit {
+            config.min_deposit = value;
+        }
+        if let Some(value) = patch.max_deposit {
+            config.max_deposit = value;
+        }
+        if let Some(value) = patch.epochs_wait {
+            config.epochs_wait = value;
+        }
+        if let Some(value) = patch.time_between_raffles {
+            config.time_between_raffles = value.0;
+        }
+        if let Some(value) = patch.batch_deposits {
+            config.batch_deposits = value;
+        }
+        if let Some(value) = patch.ping_before_prize {
+            config.ping_before_prize = value;
+        }
+        if let Some(value) = patch.prize_gas {
+            config.prize_gas = value;
+        }
+        if let Some(value) = patch.health_policy {
+            config.health_policy = value;
+        }
+        if let Some(value) = patch.timelock_delay {
+            config.timelock_delay = value.0;
+        }
+        if let Some(value) = patch.pool_fee {
+            self.pool.pool_fee = value;
+        }
+    }
+
+    pub(crate) fn validate_config(&self) {
+        let config = &self.config;
+
+        require!(
+            self.pool.pool_fee <= MAX_POOL_FEE,
+            "The fee cannot exceed 100%"
+        );
+        require!(
+            config.min_to_raffle <= config.max_to_raffle,
+            "min_to_raffle cannot exceed max_to_raffle"
+        );
+        require!(!config.min_deposit.is_zero(), "min_deposit cannot be zero");
+        require!(
+            config.min_deposit <= config.max_deposit,
+            "min_deposit cannot exceed max_deposit"
+        );
+        require!(
+            config.time_between_raffles > 0,
+            "time_between_raffles cannot be zero"
+        );
+        require!(
+            (MIN_EPOCHS_WAIT..=MAX_EPOCHS_WAIT).contains(&config.epochs_wait),
+            format!(
+                "epochs_wait must be between {} and {}",
+                MIN_EPOCHS_WAIT, MAX_EPOCHS_WAIT
+            )
+        );
+        require!(
+            config.prize_gas.get_account > 0 && config.prize_gas.callback > 0,
+            "Gas cannot be zero"
+        );
+        require!(
+            !config.ping_before_prize || config.prize_gas.ping > 0,
+            "Ping gas cannot be zero while ping_before_prize is enabled"
+        );
+        require!(
+            self.prize_update_gas() <= 300,
+            "Gas exceeds the transaction limit"
+        );
+        require!(
+            config.health_policy.failure_threshold > 0,
+            "failure_threshold cannot be zero"
+        );
+        require!(config.timelock_delay > 0, "timelock_delay cannot be zero");
+        require!(
+            config.timelock_delay <= MAX_TIMELOCK_DELAY,
+            "timelock_delay cannot exceed 30 days"
+        );
+    }
+
+    // Flat view of the parameters, to report what changed
+    fn config_values(&self) -> Map<String, Value> {
+        let mut values = match json!(self.config) {
+            Value::Object(values) => values,
+            _ => unreachable!(),
+        };
+        values.insert("pool_fee".to_string(), json!(self.pool.pool_fee));
+        values
+    }
+}
+
+impl ConfigPatch {
+    // Raising the delay only gives users more time, lowering it is timelocked
+    pub(crate) fn is_sensitive(&self, config: &Config) -> bool {
+        self.pool_fee.is_some()
+            || self.min_deposit.is_some()
+            || self.max_deposit.is_some()
+            || self
+                .timelock_delay
+                .is_some_and(|delay| delay.0 < config.timelock_delay)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pool_fee.is_none() && self.timelock_delay.is_none() && !self.has_parameters()
+    }
+
+    fn has_parameters(&self) -> bool {
+        self.min_to_raffle.is_some()
+            || self.max_to_raffle.is_some()
+            || self.min_deposit.is_some()
+            || self.max_deposit.is_some()
+            || self.epochs_wait.is_some()
+            || self.time_between_raffles.is_some()
+            || self.batch_deposits.is_some()
+            || self.ping_before_prize.is_some()
+            || self.prize_gas.is_some()
+            || self.health_policy.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_update_config() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        contract.update_config(ConfigPatch {
+            min_to_raffle: Some(NearToken::from_near(2)),
+            epochs_wait: Some(6),
+            ..Default::default()
+        });
+
+        let config = contract.get_config();
+        assert_eq!(config.min_to_raffle, NearToken::from_near(2));
+        assert_eq!(config.max_to_raffle, MAX_TO_RAFFLE);
+        assert_eq!(config.epochs_wait, 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "The fee cannot exceed 100%")]
+    fn test_update_config_fee_bound() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        // Rejected when proposed, not once the delay passed
+        contract.set_pool_fee(250);
+    }
+
+    #[test]
+    #[should_panic(expected = "min_deposit cannot exceed max_deposit")]
+    fn test_update_config_deposit_bounds() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        contract.propose_change(Change::Config(ConfigPatch {
+            max_deposit: Some(NearToken::from_millinear(500)),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    #[should_panic(expected = "timelock_delay cannot be zero")]
+    fn test_timelock_delay_required() {
+        set_context();
+        Contract::new(
+            accounts(0),
+            "guardian".parse().unwrap(),
+            U64(0),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(U64(0)),
+        );
+    }
+
+    #[test]
+    fn test_ping_before_prize() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        // 40 for the callback plus 15 + 5 to query the validator
+        assert_eq!(contract.prize_update_gas(), 60);
+
+        contract.set_ping_before_prize(true);
+        assert!(contract.get_config().ping_before_prize);
+        // The ping and its callback are added for each validator
+        assert_eq!(contract.prize_update_gas(), 80);
+
+        contract.set_prize_gas(10, 20, 30);
+        let gas = &contract.config.prize_gas;
+        assert_eq!((gas.ping, gas.get_account, gas.callback), (10, 20, 30));
+        assert_eq!(contract.prize_update_gas(), 70);
+
+        contract.set_ping_before_prize(false);
+        assert_eq!(contract.prize_update_gas(), 55);
+    }
+
+    #[test]
+    #[should_panic(expected = "Ping gas cannot be zero while ping_before_prize is enabled")]
+    fn test_ping_needs_gas() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        contract.set_ping_before_prize(true);
+        contract.set_prize_gas(0, 15, 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Gas exceeds the transaction limit")]
+    fn test_prize_gas_limit() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        contract.set_ping_before_prize(true);
+        contract.set_prize_gas(100, 100, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "The patch changes nothing")]
+    fn test_update_config_empty() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        testing_env!(context(&accounts(1)).build());
+        contract.update_config(ConfigPatch::default());
+    }
+
+    fn set_context() {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::*;
+use near_sdk::{
+    near, require,
+    serde_json::{Map, Value},
+};
+
+#[near]
+impl Contract {
+    pub fn get_dao(&self) -> Option<AccountId> {
+        self.config.dao.clone()
+    }
+
+    // Proposals of the current DAO, a new DAO starts its own count
+    pub fn is_dao_proposal_executed(&self, proposal_id: u64) -> bool {
+        self.config
+            .dao
+            .clone()
+            .is_some_and(|dao| self.dao_proposals.contains(&(dao, proposal_id)))
+    }
+
+    // Target of the function calls in the DAO proposals. The DAO still needs
+    // the role the change requires, and what `update_config` could not change
+    // directly waits in our timelock. Returns the timelocked proposal, if any
+    pub fn dao_execute(&mut self, proposal_id: u64, change: Change) -> Option<u64> {
+        let dao = env::predecessor_account_id();
+        require!(
+            self.config.dao.as_ref() == Some(&dao),
+            "Only the DAO can execute its proposals"
+        );
+        self.require_change_roles(&change);
+        require!(
+            self.dao_proposals.insert((dao.clone(), proposal_id)),
+            "Proposal already executed"
+        );
+
+        let timelocked = match &change {
+            Change::Config(patch) => patch.is_sensitive(&self.config),
+            _ => true,
+        };
+
+        let (proposal, mut old, mut new) = match timelocked {
+            true => {
+                let id = self.propose_change(change.clone());
+                (
+                    Some(id),
+                    Map::new(),
+                    Map::from_iter([("proposal".to_string(), json!(id))]),
+                )
+            }
+            false => {
+                let (old, new) = self.apply_change(change.clone());
+                (None, old, new)
+            }
+        };
+        old.insert("dao_proposal".to_string(), json!(proposal_id));
+        new.insert("dao_proposal".to_string(), json!(proposal_id));
+        self.record_admin_action("dao_execute", Value::Object(old), Value::Object(new));
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "dao_proposal_executed",
+            "data": {
+                "dao": &dao,
+                "proposal_id": proposal_id,
+                "change": &change,
+                "timelock_proposal": proposal,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        proposal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use crate::config::ConfigPatch;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    const DELAY: u64 = TIMELOCK_DELAY;
+
+    #[test]
+    fn test_dao_execute() {
+        let dao: AccountId = "dao".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let id = contract.propose_change(Change::Dao(Some(dao.clone())));
+        set_context(&"contract".parse().unwrap(), DELAY);
+        contract.execute_proposal(id);
+        contract.grant_role(Role::FeeManager, dao.clone());
+        contract.grant_role(Role::Operator, dao.clone());
+        contract.validators[0].staked = NearToken::from_near(1);
+
+        set_context(&dao, DELAY);
+        let epochs = contract.dao_execute(
+            6,
+            Change::Config(ConfigPatch {
+                epochs_wait: Some(6),
+                ..Default::default()
+            }),
+        );
+        let fee = contract.dao_execute(
+            7,
+            Change::Config(ConfigPatch {
+                pool_fee: Some(5),
+                ..Default::default()
+            }),
+        );
+        let migration = contract.dao_execute(
+            8,
+            Change::Migration {
+                from: accounts(0),
+                to: accounts(1),
+            },
+        );
+
+        // Parameters change right away, the fee and the migration wait the delay
+        assert!(epochs.is_none());
+        assert_eq!(contract.config.epochs_wait, 6);
+        assert_ne!(contract.pool.pool_fee, 5);
+        assert!(contract.is_dao_proposal_executed(7));
+        assert_eq!(contract.get_proposal(fee.unwrap()).unwrap().proposer, dao);
+
+        set_context(&accounts(4), 2 * DELAY);
+        contract.execute_proposal(fee.unwrap());
+        contract.execute_proposal(migration.unwrap());
+        assert_eq!(contract.pool.pool_fee, 5);
+
+        // Migrations still wait for the guardians
+        assert!(contract.get_migration().is_none());
+        set_context(&"guardian".parse().unwrap(), 2 * DELAY);
+        contract.approve_action(0);
+        assert_eq!(contract.get_migration().unwrap().to, accounts(1));
+
+        let log = contract.get_audit_log(0, 20);
+        let dao_log: Vec<_> = log
+            .iter()
+            .filter(|action| action.method == "dao_execute")
+            .collect();
+        assert_eq!(dao_log.last().unwrap().new["dao_proposal"], 8);
+        assert_eq!(dao_log.last().unwrap().new["proposal"], migration.unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Proposal already executed")]
+    fn test_dao_proposal_runs_once() {
+        let dao: AccountId = "dao".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let id = contract.propose_change(Change::Dao(Some(dao.clone())));
+        set_context(&"contract".parse().unwrap(), DELAY);
+        contract.execute_proposal(id);
+        contract.grant_role(Role::FeeManager, dao.clone());
+
+        set_context(&dao, DELAY);
+        let change = Change::Config(ConfigPatch {
+            pool_fee: Some(5),
+            ..Default::default()
+        });
+        contract.dao_execute(1, change.clone());
+        contract.dao_execute(1, change);
+    }
+
+    #[test]
+    fn test_dao_replaced() {
+        let dao: AccountId = "dao".parse().unwrap();
+        let new_dao: AccountId = "new-dao".parse().unwrap();
+        let change = Change::Config(ConfigPatch {
+            pool_fee: Some(5),
+            ..Default::default()
+        });
+
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let id = contract.propose_change(Change::Dao(Some(dao.clone())));
+        set_context(&"contract".parse().unwrap(), DELAY);
+        contract.execute_proposal(id);
+        contract.grant_role(Role::FeeManager, dao.clone());
+        contract.grant_role(Role::FeeManager, new_dao.clone());
+
+        set_context(&dao, DELAY);
+        contract.dao_execute(1, change.clone());
+        assert!(contract.is_dao_proposal_executed(1));
+
+        set_context(&"contract".parse().unwrap(), DELAY);
+        let id = contract.propose_change(Change::Dao(Some(new_dao.clone())));
+        set_context(&"contract".parse().unwrap(), 2 * DELAY);
+        contract.execute_proposal(id);
+        assert!(!contract.is_dao_proposal_executed(1));
+
+        // The new DAO numbers its proposals from scratch
+        set_context(&new_dao, 2 * DELAY);
+        contract.dao_execute(1, change);
+        assert!(contract.is_dao_proposal_executed(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the DAO can execute its proposals")]
+    fn test_dao_only() {
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        contract.dao_execute(0, Change::Whitelist(None));
+    }
+
+    fn set_context(account: &AccountId, timestamp_ms: u64) {
+        testing_env!(context(account)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
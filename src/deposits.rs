@@ -0,0 +1,237 @@
+use crate::journal::CallKind;
+use crate::lock::Operation;
+use crate::*;
+use near_sdk::{near, require, serde_json::json, store::IterableMap, Gas, Promise, PromiseError};
+
+// Maximum number of users credited in a single sweep, so the
+// callback never runs out of gas while staking their tickets
+const MAX_DEPOSITS_PER_SWEEP: usize = 20;
+
+#[near(serializers=[borsh])]
+pub struct Deposits {
+    // Deposits waiting for the next sweep
+    pub queued: IterableMap<AccountId, NearToken>,
+    // Deposits sent to the external pool, waiting for the callback
+    pub sweeping: Vector<(AccountId, NearToken)>,
+}
+
+impl Default for Deposits {
+    fn default() -> Self {
+        Self {
+            queued: IterableMap::new(StorageKey::QueuedDeposits),
+            sweeping: Vector::new(StorageKey::SweepingDeposits),
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    // Keepers can sweep the queued deposits at any time
+    pub fn sweep_deposits(&mut self) -> Promise {
+        self.require_not_paused(PauseFlag::External);
+
+        self.start_sweep()
+    }
+
+    #[private]
+    pub fn sweep_deposits_callback(
+        &mut self,
+        amount: NearToken,
+        validator: AccountId,
+        entry_id: u64,
+        lock_id: u64,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
+        self.stop_interacting(lock_id);
+
+        let batch: Vec<(AccountId, NearToken)> = self.deposits.sweeping.drain(..).collect();
+
+        if call_result.is_err() {
+            // Put the deposits back in the queue, the next sweep retries them
+            log!("Failed to sweep deposits into the external pool");
+            for (user, tickets) in batch {
+                self.queue_deposit_for(&user, tickets);
+            }
+            return false;
+        }
+
+        let idx = self.validator_index(&validator);
+        self.validators[idx].staked = self.validators[idx].staked.saturating_add(amount);
+        self.pool.tickets = self.pool.tickets.saturating_add(amount);
+
+        for (user, tickets) in batch {
+            self.remove_pending_deposit_for(&user, tickets);
+            self.stake_tickets_for(&user, tickets.as_yoctonear());
+
+            let event_args = json!({
+                "standard": "nep297",
+                "version": "1.0.0",
+                "event": "stake_for_user",
+                "data": {
+                    "user": &user,
+                    "amount": &tickets,
+                },
+            });
+
+            log!("EVENT_JSON:{}", event_args.to_string());
+        }
+
+        true
+    }
+
+    pub fn get_queued_deposits(&self, from: usize, until: usize) -> Vec<(AccountId, NearToken)> {
+        self.deposits
+            .queued
+            .iter()
+            .skip(from)
+            .take(until.saturating_sub(from))
+            .map(|(user, tickets)| (user.clone(), *tickets))
+            .collect()
+    }
+
+    pub(crate) fn has_deposits_to_sweep(&self) -> bool {
+        self.deposits.sweeping.is_empty() && !self.deposits.queued.is_empty()
+    }
+
+    pub(crate) fn queue_deposit_for(&mut self, user: &AccountId, tickets: NearToken) {
+        let queued = self
+            .deposits
+            .queued
+            .get(user)
+            .copied()
+            .unwrap_or(NearToken::from_yoctonear(0));
+
+        self.deposits
+            .queued
+            .insert(user.clone(), queued.saturating_add(tickets));
+        self.pool.queued_deposits = self.pool.queued_deposits.saturating_add(tickets);
+    }
+
+    // Stakes a batch of queued deposits in a single call to the external pool
+    pub(crate) fn start_sweep(&mut self) -> Promise {
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(220)),
+            "Use at least 220Tgas"
+        );
+        require!(
+            self.deposits.sweeping.is_empty(),
+            "Already sweeping deposits"
+        );
+        let lock_id = self.start_interacting(Operation::Sweep);
+
+        let batch: Vec<(AccountId, NearToken)> = self
+            .deposits
+            .queued
+            .iter()
+            .take(MAX_DEPOSITS_PER_SWEEP)
+            .map(|(user, tickets)| (user.clone(), *tickets))
+            .collect();
+
+        require!(!batch.is_empty(), "Nothing to sweep");
+
+        let mut amount = NearToken::from_yoctonear(0);
+        for (user, tickets) in batch {
+            self.deposits.queued.remove(&user);
+            self.deposits.sweeping.push((user, tickets));
+            amount = amount.saturating_add(tickets);
+        }
+
+        // From now on the deposits might be in the external pool
+        self.pool.queued_deposits = self.pool.queued_deposits.saturating_sub(amount);
+
+        let validator = self.validator_for_deposit(amount);
+        let entry_id = self.record_call(CallKind::Sweep, &validator, amount);
+
+        Promise::new(validator.clone())
+            .function_call(
+                "deposit_and_stake".to_string(),
+                NO_ARGS,
+                amount,
+                Gas::from_tgas(120),
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "sweep_deposits_callback".to_string(),
+                    json!({
+                        "amount": amount,
+                        "validator": validator,
+                        "entry_id": entry_id,
+                        "lock_id": lock_id,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(80),
+                ),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_batched_deposits() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap(), NearToken::from_yoctonear(0));
+        let mut contract = Contract::for_tests();
+        contract.config.min_deposit = NearToken::from_yoctonear(1);
+        contract.set_batch_deposits(true);
+
+        set_context(&guardian, NearToken::from_yoctonear(1));
+        contract.deposit_and_stake();
+        set_context(&user, NearToken::from_yoctonear(2));
+        contract.deposit_and_stake();
+        contract.deposit_and_stake();
+
+        assert_eq!(contract.pool.pending_deposits, NearToken::from_yoctonear(5));
+        assert_eq!(contract.pool.queued_deposits, NearToken::from_yoctonear(5));
+        assert_eq!(
+            contract.get_queued_deposits(0, 10),
+            vec![
+                (guardian.clone(), NearToken::from_yoctonear(1)),
+                (user.clone(), NearToken::from_yoctonear(4))
+            ]
+        );
+
+        // A failed sweep puts the deposits back in the queue
+        contract.sweep_deposits();
+        assert_eq!(contract.pool.queued_deposits, NearToken::from_yoctonear(0));
+
+        set_context(&"contract".parse().unwrap(), NearToken::from_yoctonear(0));
+        contract.sweep_deposits_callback(
+            NearToken::from_yoctonear(5),
+            accounts(0),
+            0,
+            0,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.pool.queued_deposits, NearToken::from_yoctonear(5));
+        assert_eq!(contract.pool.tickets, NearToken::from_yoctonear(0));
+
+        // A successful sweep makes them raffle-eligible
+        contract.sweep_deposits();
+        contract.sweep_deposits_callback(NearToken::from_yoctonear(5), accounts(0), 1, 1, Ok(()));
+        assert_eq!(contract.pool.tickets, NearToken::from_yoctonear(5));
+        assert_eq!(contract.pool.pending_deposits, NearToken::from_yoctonear(0));
+        assert_eq!(contract.get_staked_for(&user), 4);
+        assert!(contract.get_queued_deposits(0, 10).is_empty());
+    }
+
+    fn set_context(account: &AccountId, attached_deposit: NearToken) {
+        testing_env!(context(account)
+            .account_balance(NearToken::from_near(20))
+            .attached_deposit(attached_deposit)
+            .build());
+    }
+}
@@ -1,25 +1,29 @@
+use crate::journal::CallKind;
+use crate::lock::Operation;
+use crate::pool::ExternalUser;
 use crate::*;
-use near_sdk::{require, serde_json::json, Gas, Promise, PromiseError};
+use near_sdk::{require, serde_json::json, Gas, GasWeight, Promise, PromiseError, PromiseOrValue};
 
 #[near]
 impl Contract {
-    // Semaphore to interact with external pool
-    pub(crate) fn start_interacting(&mut self) {
-        require!(
-            !self.pool.is_interacting,
-            "Already interacting with the staking contract"
-        );
-
-        self.pool.is_interacting = true;
-    }
-
-    pub(crate) fn stop_interacting(&mut self) {
-        self.pool.is_interacting = false;
-    }
-
     // Interact with external pool ------------------------------------------------
     pub fn interact_external(&mut self) -> Promise {
-        require!(!self.config.emergency, "We'll be back soon");
+        self.require_not_paused(PauseFlag::External);
+
+        // Scheduled unstakes that triggered join this turn's unstake
+        self.process_scheduled_unstakes();
+
+        // Sweeps and turns alternate, so a steady flow of deposits cannot
+        // hold back the users waiting to unstake or withdraw
+        let turn_ready = match self.next_action {
+            Action::Unstake => !self.pool.to_unstake.is_zero(),
+            Action::Withdraw => true,
+        };
+        if self.has_deposits_to_sweep() && (!self.pool.swept_last || !turn_ready) {
+            self.pool.swept_last = true;
+            return self.start_sweep();
+        }
+        self.pool.swept_last = false;
 
         match self.next_action {
             Action::Unstake => self.unstake_external(),
@@ -37,97 +41,391 @@ impl Contract {
         );
 
         // Check if we are already interacting
-        self.start_interacting();
+        let lock_id = self.start_interacting(Operation::Unstake);
 
         self.pool.next_withdraw_turn += 1;
 
-        Promise::new(self.config.external_pool.clone())
-            .function_call(
-                "unstake".to_string(),
-                json!({ "amount": self.pool.to_unstake})
-                    .to_string()
-                    .into_bytes(),
-                NO_DEPOSIT,
-                Gas::from_tgas(120),
-            )
-            .then(
-                Promise::new(env::current_account_id()).function_call(
-                    "unstake_external_callback".to_string(),
-                    json!({ "amount": self.pool.to_unstake})
-                        .to_string()
-                        .into_bytes(),
-                    NO_DEPOSIT,
-                    Gas::from_tgas(45), // Todo: Check the Gas amount
-                ),
-            )
+        // Each validator unstakes its share, the turn ends when all of them answered
+        let shares = self.split_unstake(self.pool.to_unstake);
+
+        let calls = shares
+            .into_iter()
+            .map(|(validator, amount)| {
+                let entry_id = self.record_call(CallKind::Unstake, &validator, amount);
+
+                let call = Promise::new(validator.clone())
+                    .function_call_weight(
+                        "unstake".to_string(),
+                        json!({ "amount": amount }).to_string().into_bytes(),
+                        NO_DEPOSIT,
+                        Gas::from_tgas(30),
+                        GasWeight(1),
+                    )
+                    .then(
+                        Promise::new(env::current_account_id()).function_call(
+                            "unstake_external_callback".to_string(),
+                            json!({
+                                "validator": validator,
+                                "amount": amount,
+                                "entry_id": entry_id,
+                                "lock_id": lock_id,
+                            })
+                            .to_string()
+                            .into_bytes(),
+                            NO_DEPOSIT,
+                            Gas::from_tgas(20), // Todo: Check the Gas amount
+                        ),
+                    );
+
+                (entry_id, call)
+            })
+            .collect();
+
+        self.join_external_calls(calls)
     }
 
     #[private]
     pub fn unstake_external_callback(
         &mut self,
+        validator: AccountId,
         amount: NearToken,
+        entry_id: u64,
+        lock_id: u64,
         #[callback_result] call_result: Result<(), PromiseError>,
     ) {
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return;
+        }
+
         if call_result.is_err() {
-            log!("Error while unstaking from external pool");
-            // Rollback next_withdraw_turn
-            self.pool.next_withdraw_turn -= 1;
+            // Its share stays in to_unstake for the next turn
+            log!("Error while unstaking from external pool {}", &validator);
         } else {
+            let idx = self.validator_index(&validator);
+            self.validators[idx].staked = self.validators[idx].staked.saturating_sub(amount);
+            self.validators[idx].unstaking = self.validators[idx].unstaking.saturating_add(amount);
+
             self.pool.tickets = self.pool.tickets.saturating_sub(amount);
+            self.pool.to_unstake = self.pool.to_unstake.saturating_sub(amount);
+        }
+
+        // A newer turn owns `turn_calls` once our lock expired
+        if !self.holds_lock(lock_id) || !self.is_turn_finished() {
+            return;
+        }
+
+        if !self.turn_succeeded() {
+            // Their funds are not all unstaked, the users stay in the turn we
+            // opened and the failed shares are retried before anyone withdraws
+            self.pool.next_withdraw_turn -= 1;
+        } else {
             self.pool.next_withdraw_epoch = env::epoch_height() + self.config.epochs_wait;
 
             // next time we want to withdraw
             self.next_action = Action::Withdraw;
-
-            self.pool.to_unstake = self.pool.to_unstake.saturating_sub(amount);
         }
-        self.stop_interacting();
+        self.stop_interacting(lock_id);
     }
 
     // Withdraw external ----------------------------------------------------------
     fn withdraw_external(&mut self) -> Promise {
         require!(env::prepaid_gas() >= Gas::from_tgas(300), "Not enough gas"); // TODO: evaluate
 
-        // Check that 6 epochs passed from the last unstake from external
-        require!(
-            env::epoch_height() >= self.pool.next_withdraw_epoch,
-            "Not enough time has passed"
-        );
-
         // Check if we are already interacting, if not, set it to true()
-        self.start_interacting();
+        let lock_id = self.start_interacting(Operation::Withdraw);
 
-        // Withdraw tokens from external pool
-        Promise::new(self.config.external_pool.clone())
-            .function_call(
-                "withdraw_all".to_string(),
-                NO_ARGS,
-                NO_DEPOSIT,
-                Gas::from_tgas(120), // Todo: Check the Gas amount
-            )
-            .then(Promise::new(env::current_account_id()).function_call(
-                "withdraw_external_callback".to_string(),
-                NO_ARGS,
+        // Ask every validator we unstaked from if the funds can be withdrawn,
+        // our epoch estimate might be off and a failed withdraw wastes the turn
+        let validators: Vec<AccountId> = self
+            .validators
+            .iter()
+            .filter(|validator| !validator.unstaking.is_zero())
+            .map(|validator| validator.account_id.clone())
+            .collect();
+
+        let (entries, queries) = self.query_accounts(&validators, Gas::from_tgas(10));
+
+        queries.then(
+            Promise::new(env::current_account_id()).function_call(
+                "withdraw_external_check".to_string(),
+                json!({ "validators": validators, "lock_id": lock_id, "entries": entries })
+                    .to_string()
+                    .into_bytes(),
                 NO_DEPOSIT,
-                Gas::from_tgas(120), // Todo: Check the Gas amount
-            ))
+                Gas::from_tgas(25 + 50 * validators.len() as u64),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn withdraw_external_check(
+        &mut self,
+        validators: Vec<AccountId>,
+        lock_id: u64,
+        entries: Vec<u64>,
+    ) -> PromiseOrValue<bool> {
+        let accounts = self.read_accounts(&entries);
+
+        // The lock expired, a newer turn might be withdrawing already
+        if !self.holds_lock(lock_id) {
+            log!("The withdraw lock expired, nothing was withdrawn");
+            return PromiseOrValue::Value(false);
+        }
+
+        let ready = self.reconcile_withdrawals(validators, accounts);
+
+        if ready.is_empty() {
+            // Nothing can be withdrawn yet, the turn is not consumed
+            self.stop_interacting(lock_id);
+            return PromiseOrValue::Value(false);
+        }
+
+        let calls = ready
+            .into_iter()
+            .map(|validator| {
+                let unstaking = self.validators[self.validator_index(&validator)].unstaking;
+                let entry_id = self.record_call(CallKind::Withdraw, &validator, unstaking);
+
+                let call = Promise::new(validator.clone())
+                    .function_call_weight(
+                        "withdraw_all".to_string(),
+                        NO_ARGS,
+                        NO_DEPOSIT,
+                        Gas::from_tgas(30),
+                        GasWeight(1),
+                    )
+                    .then(
+                        Promise::new(env::current_account_id()).function_call(
+                            "withdraw_external_callback".to_string(),
+                            json!({
+                                "validator": validator,
+                                "entry_id": entry_id,
+                                "lock_id": lock_id,
+                            })
+                            .to_string()
+                            .into_bytes(),
+                            NO_DEPOSIT,
+                            Gas::from_tgas(20), // Todo: Check the Gas amount
+                        ),
+                    );
+
+                (entry_id, call)
+            })
+            .collect();
+
+        PromiseOrValue::Promise(self.join_external_calls(calls))
     }
 
     #[private]
     pub fn withdraw_external_callback(
         &mut self,
+        validator: AccountId,
+        entry_id: u64,
+        lock_id: u64,
         #[callback_result] call_result: Result<(), PromiseError>,
     ) -> bool {
-        self.stop_interacting();
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
 
         if call_result.is_err() {
-            // Rollback next_withdraw_epoch
-            self.pool.next_withdraw_epoch -= 1;
-            false
+            log!("Error while withdrawing from external pool {}", &validator);
         } else {
+            let idx = self.validator_index(&validator);
+            self.validators[idx].unstaking = NearToken::from_yoctonear(0);
+        }
+
+        // A newer turn owns `turn_calls` once our lock expired
+        if self.holds_lock(lock_id) && self.is_turn_finished() {
+            // Validators that failed are retried before the next unstake
+            self.finish_withdraw_turn();
+            self.stop_interacting(lock_id);
+        }
+
+        call_result.is_ok()
+    }
+
+    // Updates our view of the unstaked funds with what the validators report,
+    // returning the validators whose funds can be withdrawn right now
+    fn reconcile_withdrawals(
+        &mut self,
+        validators: Vec<AccountId>,
+        accounts: Vec<Option<ExternalUser>>,
+    ) -> Vec<AccountId> {
+        let mut ready = Vec::new();
+        let mut locked = false;
+
+        for (validator, account) in validators.into_iter().zip(accounts) {
+            match account {
+                None => {
+                    log!("Failed to query the external pool {}", &validator);
+                    locked = true;
+                }
+                Some(account) if account.unstaked_balance.is_zero() => {
+                    // The funds already arrived, e.g. a withdraw callback failed
+                    let idx = self.validator_index(&validator);
+                    self.validators[idx].unstaking = NearToken::from_yoctonear(0);
+                }
+                Some(account) if !account.can_withdraw => locked = true,
+                Some(_) => ready.push(validator),
+            }
+        }
+
+        // Users wait at least until the next epoch
+        if locked {
+            self.pool.next_withdraw_epoch =
+                self.pool.next_withdraw_epoch.max(env::epoch_height() + 1);
+        }
+
+        if ready.is_empty() {
+            self.finish_withdraw_turn();
+        }
+
+        ready
+    }
+
+    // Once every validator returned the funds, the next unstake turn can start
+    fn finish_withdraw_turn(&mut self) {
+        if self.validators.iter().all(|v| v.unstaking.is_zero()) {
             self.next_action = Action::Unstake;
             self.pool.next_withdraw_turn += 1;
-            true
+
+            // The funds are here, no need to wait for our estimate
+            self.pool.next_withdraw_epoch = self.pool.next_withdraw_epoch.min(env::epoch_height());
+        }
+    }
+
+    // Runs the calls to the validators in parallel, remembering the journal
+    // entries the turn waits for
+    fn join_external_calls(&mut self, calls: Vec<(u64, Promise)>) -> Promise {
+        let (entries, calls): (Vec<u64>, Vec<Promise>) = calls.into_iter().unzip();
+        self.pool.turn_calls = entries;
+
+        calls
+            .into_iter()
+            .reduce(|joint, call| joint.and(call))
+            .expect("No validator to interact with")
+    }
+
+    // The turn ends once every entry of the turn is completed
+    fn is_turn_finished(&self) -> bool {
+        !self
+            .pool
+            .turn_calls
+            .iter()
+            .any(|id| self.is_pending_call(*id))
+    }
+
+    fn turn_succeeded(&self) -> bool {
+        self.pool
+            .turn_calls
+            .iter()
+            .all(|id| self.is_succeeded_call(*id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_reconcile_withdrawals() {
+        set_context(5);
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+        contract.insert_validator(accounts(2), 1);
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        for validator in contract.validators.iter_mut() {
+            validator.unstaking = near(1);
         }
+        contract.next_action = Action::Withdraw;
+        contract.pool.next_withdraw_epoch = 4;
+
+        let account = |idx: usize, unstaked: u128, can_withdraw: bool| {
+            Some(ExternalUser {
+                account_id: accounts(idx),
+                unstaked_balance: near(unstaked),
+                staked_balance: near(0),
+                can_withdraw,
+            })
+        };
+
+        // Our estimate said the funds were free, but one validator still holds them
+        let ready = contract.reconcile_withdrawals(
+            vec![accounts(0), accounts(1), accounts(2)],
+            vec![
+                account(0, 1, true),
+                account(1, 1, false),
+                account(2, 0, true),
+            ],
+        );
+        assert_eq!(ready, vec![accounts(0)]);
+        assert_eq!(contract.pool.next_withdraw_epoch, 6);
+        assert_eq!(contract.validators[2].unstaking, near(0));
+
+        // Users keep waiting until the released funds are withdrawn
+        contract.validators[0].unstaking = near(0);
+        let ready = contract.reconcile_withdrawals(vec![accounts(1)], vec![account(1, 1, true)]);
+        assert_eq!(ready, vec![accounts(1)]);
+        assert_eq!(contract.pool.next_withdraw_epoch, 6);
+        assert_eq!(contract.next_action, Action::Withdraw);
+
+        // Funds that already arrived end the turn without calling the validator,
+        // and users do not wait for the estimate
+        let ready = contract.reconcile_withdrawals(vec![accounts(1)], vec![account(1, 0, false)]);
+        assert!(ready.is_empty());
+        assert_eq!(contract.next_action, Action::Unstake);
+        assert_eq!(contract.pool.next_withdraw_turn, 2);
+        assert_eq!(contract.pool.next_withdraw_epoch, 5);
+    }
+
+    #[test]
+    fn test_sweeps_alternate_with_turns() {
+        set_context(5);
+        let mut contract = Contract::for_tests();
+        contract.validators[0].staked = NearToken::from_near(1);
+        contract.pool.to_unstake = NearToken::from_near(1);
+
+        let sweep_done = |contract: &mut Contract| {
+            contract.deposits.sweeping.clear();
+            contract.stop_interacting(contract.get_locks()[0].id);
+        };
+
+        // Deposits keep arriving, yet the unstake turn gets its chance
+        contract.queue_deposit_for(&accounts(1), NearToken::from_near(1));
+        set_context(5);
+        contract.interact_external();
+        assert!(contract.is_running(&Operation::Sweep));
+        sweep_done(&mut contract);
+
+        contract.queue_deposit_for(&accounts(2), NearToken::from_near(1));
+        set_context(5);
+        contract.interact_external();
+        assert!(contract.is_running(&Operation::Unstake));
+        assert!(!contract.is_running(&Operation::Sweep));
+        contract.stop_interacting(contract.get_locks()[0].id);
+
+        set_context(5);
+        contract.interact_external();
+        assert!(contract.is_running(&Operation::Sweep));
+        sweep_done(&mut contract);
+
+        // Without a turn to run the deposits do not wait
+        contract.pool.to_unstake = NearToken::from_near(0);
+        contract.queue_deposit_for(&accounts(3), NearToken::from_near(1));
+        set_context(5);
+        contract.interact_external();
+        assert!(contract.is_running(&Operation::Sweep));
+    }
+
+    fn set_context(epoch: u64) {
+        testing_env!(context(&"contract".parse().unwrap())
+            .epoch_height(epoch)
+            .build());
     }
 }
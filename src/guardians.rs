@@ -0,0 +1,494 @@
+use crate::*;
+use near_sdk::{
+    json_types::Base58CryptoHash,
+    near, require,
+    serde_json::{json, Map, Value},
+    CryptoHash, Gas, GasWeight, Promise,
+};
+
+// Keeps the pending actions cheap to store and to review
+const MAX_PENDING_ACTIONS: usize = 20;
+const MAX_GUARDIANS: usize = 10;
+
+// Actions that do not gather their approvals in time expire (3 days in ms)
+const APPROVAL_WINDOW: u64 = 259_200_000;
+const MAX_APPROVAL_WINDOW: u64 = 2_592_000_000;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub enum CriticalAction {
+    // Unstakes part of the pool reserve, the reserve account withdraws it as usual
+    ReserveWithdrawal(NearToken),
+    // Opened by an executed `Change::Migration`
+    Migration { from: AccountId, to: AccountId },
+    // Lifts the pauses, `emergency_stop` proposes all of them
+    Resume(Vec<PauseFlag>),
+    // Hash of the code `upgrade` deploys
+    Upgrade(Base58CryptoHash),
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct PendingAction {
+    pub id: u64,
+    pub action: CriticalAction,
+    pub proposer: AccountId,
+    pub approvals: Vec<AccountId>,
+    // Block timestamps (ms)
+    pub proposed_at: u64,
+    pub expires_at: u64,
+}
+
+#[near(serializers=[borsh])]
+pub struct Guardians {
+    pub members: Vec<AccountId>,
+    // Distinct approvals an action needs to execute
+    pub threshold: u32,
+    pub approval_window: u64,
+    pub next_id: u64,
+    pub pending: Vec<PendingAction>,
+    pub approved_upgrade: Option<CryptoHash>,
+}
+
+#[near(serializers=[json])]
+pub struct GuardiansInfo {
+    pub members: Vec<AccountId>,
+    pub threshold: u32,
+    pub approval_window: U64,
+    pub approved_upgrade: Option<Base58CryptoHash>,
+}
+
+impl Guardians {
+    // A single guardian approves its actions alone
+    pub fn new(guardian: AccountId) -> Self {
+        Self {
+            members: vec![guardian],
+            threshold: 1,
+            approval_window: APPROVAL_WINDOW,
+            next_id: 0,
+            pending: vec![],
+            approved_upgrade: None,
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_guardians(&self) -> GuardiansInfo {
+        GuardiansInfo {
+            members: self.guardians.members.clone(),
+            threshold: self.guardians.threshold,
+            approval_window: U64(self.guardians.approval_window),
+            approved_upgrade: self.guardians.approved_upgrade.map(Base58CryptoHash::from),
+        }
+    }
+
+    // The expired actions are left out
+    pub fn get_pending_actions(&self) -> Vec<PendingAction> {
+        let now = env::block_timestamp_ms();
+
+        self.guardians
+            .pending
+            .iter()
+            .filter(|pending| pending.expires_at > now)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_pending_action(&self, id: u64) -> Option<PendingAction> {
+        self.get_pending_actions()
+            .into_iter()
+            .find(|pending| pending.id == id)
+    }
+
+    // The proposal counts as the proposer's approval, migrations
+    // are timelocked first and come from `start_migration`
+    pub fn propose_action(&mut self, action: CriticalAction) -> u64 {
+        self.require_guardian();
+        require!(
+            !matches!(action, CriticalAction::Migration { .. }),
+            "Migrations are timelocked, use start_migration"
+        );
+
+        let id = self.open_action(action);
+        self.record_admin_action(
+            "propose_action",
+            Value::Null,
+            json!(self.guardians.pending.last()),
+        );
+        self.approve_action(id);
+
+        id
+    }
+
+    // The approval that reaches the threshold executes the action
+    pub fn approve_action(&mut self, id: u64) {
+        self.require_guardian();
+        let guardian = env::predecessor_account_id();
+
+        let idx = self
+            .guardians
+            .pending
+            .iter()
+            .position(|pending| pending.id == id)
+            .expect("Action not found");
+
+        let pending = &mut self.guardians.pending[idx];
+        require!(
+            env::block_timestamp_ms() < pending.expires_at,
+            "The approval window expired"
+        );
+        require!(!pending.approvals.contains(&guardian), "Already approved");
+
+        pending.approvals.push(guardian);
+        let pending = pending.clone();
+
+        self.record_admin_action(
+            "approve_action",
+            Value::Null,
+            json!({ "action": id, "approvals": pending.approvals.len() }),
+        );
+        self.log_action("action_approved", &pending);
+
+        if pending.approvals.len() < self.guardians.threshold as usize {
+            return;
+        }
+
+        self.guardians.pending.remove(idx);
+        let (mut old, mut new) = self.apply_action(pending.action.clone());
+        old.insert("action".to_string(), json!(id));
+        new.insert("action".to_string(), json!(id));
+        self.record_admin_action("execute_action", Value::Object(old), Value::Object(new));
+        self.log_action("action_executed", &pending);
+    }
+
+    // Deploys the code the guardians approved, sent as the raw input.
+    // Anyone can call it, like `execute_proposal`
+    pub fn upgrade(&mut self) -> Promise {
+        let code = env::input().expect("Missing the code");
+        let hash = env::sha256_array(&code);
+        require!(
+            self.guardians.approved_upgrade == Some(hash),
+            "The code was not approved"
+        );
+
+        self.guardians.approved_upgrade = None;
+        self.record_admin_action(
+            "upgrade",
+            json!({ "approved_upgrade": Base58CryptoHash::from(hash) }),
+            json!({ "approved_upgrade": null }),
+        );
+
+        // The new code's `migrate` reads the state this code leaves, if it
+        // fails the deployment is reverted with it
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call_weight(
+                "migrate".to_string(),
+                NO_ARGS,
+                NO_DEPOSIT,
+                Gas::from_tgas(20),
+                GasWeight(1),
+            )
+    }
+
+    // Expired actions make room for new ones
+    pub(crate) fn open_action(&mut self, action: CriticalAction) -> u64 {
+        let now = env::block_timestamp_ms();
+        self.guardians
+            .pending
+            .retain(|pending| pending.expires_at > now);
+        require!(
+            self.guardians.pending.len() < MAX_PENDING_ACTIONS,
+            "Too many pending actions"
+        );
+
+        let pending = PendingAction {
+            id: self.guardians.next_id,
+            action,
+            proposer: env::predecessor_account_id(),
+            approvals: vec![],
+            proposed_at: now,
+            expires_at: now + self.guardians.approval_window,
+        };
+
+        self.guardians.next_id += 1;
+        self.guardians.pending.push(pending.clone());
+        self.log_action("action_proposed", &pending);
+
+        pending.id
+    }
+
+    // The checks of each action run now, the state might have moved since the proposal.
+    // Returns the old and new values for the audit log
+    fn apply_action(&mut self, action: CriticalAction) -> (Map<String, Value>, Map<String, Value>) {
+        let (old, new) = match action {
+            CriticalAction::ReserveWithdrawal(amount) => {
+                let guardian = self.config.guardian.clone();
+                require!(
+                    self.shortfall.is_none(),
+                    "The pool is absorbing a loss, try again later"
+                );
+
+                let reserve = match self.is_registered(&guardian) {
+                    true => self.get_staked_for(&guardian),
+                    false => 0,
+                };
+                require!(
+                    amount.as_yoctonear() <= reserve,
+                    format!("Amount cant exceed {}", reserve)
+                );
+
+                self.unstake_for(&guardian, amount);
+                (
+                    json!({ "pool_reserve": NearToken::from_yoctonear(reserve) }),
+                    json!({ "pool_reserve": NearToken::from_yoctonear(self.get_staked_for(&guardian)) }),
+                )
+            }
+            CriticalAction::Migration { from, to } => {
+                let new = json!({ "migration": { "from": &from, "to": &to } });
+                self.begin_migration(from, to);
+                (json!({ "migration": null }), new)
+            }
+            CriticalAction::Resume(flags) => {
+                let (old, new) = self.resume_flags(&flags);
+                (json!({ "paused": old }), json!({ "paused": new }))
+            }
+            CriticalAction::Upgrade(hash) => {
+                let old = json!({
+                    "approved_upgrade": self.guardians.approved_upgrade.map(Base58CryptoHash::from)
+                });
+                self.guardians.approved_upgrade = Some(hash.into());
+                (old, json!({ "approved_upgrade": hash }))
+            }
+        };
+
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => (old, new),
+            _ => unreachable!(),
+        }
+    }
+
+    // Pending actions are dropped, the approvals of former members would count otherwise
+    pub(crate) fn replace_guardians(
+        &mut self,
+        members: Vec<AccountId>,
+        threshold: u32,
+        approval_window: u64,
+    ) {
+        require!(
+            !members.is_empty() && members.len() <= MAX_GUARDIANS,
+            format!("There must be between 1 and {} guardians", MAX_GUARDIANS)
+        );
+        require!(
+            members
+                .iter()
+                .enumerate()
+                .all(|(idx, member)| !members[..idx].contains(member)),
+            "Duplicated guardian"
+        );
+        require!(
+            threshold >= 1 && threshold as usize <= members.len(),
+            "The threshold must be between 1 and the number of guardians"
+        );
+        require!(
+            approval_window > 0 && approval_window <= MAX_APPROVAL_WINDOW,
+            "approval_window must be positive and at most 30 days"
+        );
+
+        let old = std::mem::replace(&mut self.guardians.members, members.clone());
+        for member in old.iter().filter(|member| !members.contains(member)) {
+            self.revoke_role_from(&Role::Guardian, member);
+        }
+        for member in members.iter() {
+            self.grant_role_to(Role::Guardian, member);
+        }
+
+        self.guardians.threshold = threshold;
+        self.guardians.approval_window = approval_window;
+        self.guardians.pending.clear();
+    }
+
+    // Only the members approve, the owner does not count as a guardian
+    fn require_guardian(&self) {
+        require!(
+            self.guardians
+                .members
+                .contains(&env::predecessor_account_id()),
+            "Only the guardians can approve critical actions"
+        );
+    }
+
+    fn log_action(&self, event: &str, pending: &PendingAction) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": {
+                "action": pending,
+                "threshold": self.guardians.threshold,
+                "by": env::predecessor_account_id(),
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::mock::MockAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const WINDOW: u64 = 3_600_000;
+
+    #[test]
+    fn test_guardian_approvals() {
+        let mut contract = setup();
+
+        // The reserve account is no longer a guardian, it still holds the reserve
+        let reserve: AccountId = "guardian".parse().unwrap();
+        assert!(!contract.has_role(reserve.clone(), Role::Guardian));
+        assert!(contract.has_role(accounts(2), Role::Guardian));
+
+        set_context(&accounts(1), 0);
+        let id =
+            contract.propose_action(CriticalAction::ReserveWithdrawal(NearToken::from_near(2)));
+        assert_eq!(contract.get_staked_for(&reserve), near(5));
+        assert_eq!(
+            contract.get_pending_action(id).unwrap().approvals,
+            vec![accounts(1)]
+        );
+
+        set_context(&accounts(3), 10);
+        contract.approve_action(id);
+        assert_eq!(contract.get_staked_for(&reserve), near(3));
+        assert_eq!(contract.pool.to_unstake, NearToken::from_near(2));
+        assert!(contract.get_pending_actions().is_empty());
+
+        let log = contract.get_audit_log(0, 20);
+        assert_eq!(log.last().unwrap().method, "execute_action");
+        assert_eq!(
+            log.last().unwrap().new["pool_reserve"],
+            json!(NearToken::from_near(3))
+        );
+    }
+
+    #[test]
+    fn test_timelocked_migration_needs_approvals() {
+        let mut contract = setup();
+        contract.validators[0].staked = NearToken::from_near(1);
+
+        let proposal = contract.start_migration(accounts(0), accounts(4));
+        set_context(&accounts(4), TIMELOCK_DELAY);
+        contract.execute_proposal(proposal);
+        assert!(contract.get_migration().is_none());
+
+        let id = contract.get_pending_actions()[0].id;
+        set_context(&accounts(1), TIMELOCK_DELAY);
+        contract.approve_action(id);
+        set_context(&accounts(2), TIMELOCK_DELAY);
+        contract.approve_action(id);
+
+        assert_eq!(contract.get_migration().unwrap().to, accounts(4));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut contract = setup();
+        let code = b"new code".to_vec();
+        let hash = env::sha256_array(&code);
+
+        set_context(&accounts(1), 0);
+        let id = contract.propose_action(CriticalAction::Upgrade(hash.into()));
+        set_context(&accounts(2), 0);
+        contract.approve_action(id);
+        assert_eq!(contract.get_guardians().approved_upgrade, Some(hash.into()));
+
+        let mut context = VMContextBuilder::new()
+            .predecessor_account_id(accounts(4))
+            .current_account_id("contract".parse().unwrap())
+            .prepaid_gas(Gas::from_tgas(300))
+            .build();
+        context.input = code;
+        testing_env!(context);
+
+        contract.upgrade();
+        assert!(contract.get_guardians().approved_upgrade.is_none());
+
+        // The code is deployed and migrated in the same receipt
+        let receipts = get_created_receipts();
+        assert!(matches!(
+            &receipts[0].actions[..],
+            [
+                MockAction::DeployContract { .. },
+                MockAction::FunctionCallWeight { method_name, .. },
+            ] if method_name == b"migrate"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "The approval window expired")]
+    fn test_approval_expires() {
+        let mut contract = setup();
+
+        set_context(&accounts(1), 0);
+        let id = contract.propose_action(CriticalAction::Resume(vec![PauseFlag::Deposit]));
+
+        set_context(&accounts(2), WINDOW);
+        contract.approve_action(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Already approved")]
+    fn test_distinct_approvals() {
+        let mut contract = setup();
+
+        set_context(&accounts(1), 0);
+        let id = contract.propose_action(CriticalAction::Resume(vec![PauseFlag::Deposit]));
+        contract.approve_action(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reserve withdrawals need the guardians' approval")]
+    fn test_reserve_unstake_needs_approvals() {
+        let mut contract = setup();
+
+        set_context(&"guardian".parse().unwrap(), 0);
+        contract.unstake(NearToken::from_near(1));
+    }
+
+    // Guardians 1, 2 and 3, two approvals needed, the reserve holds 5 NEAR
+    fn setup() -> Contract {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+        let mut contract = Contract::for_tests();
+
+        let id = contract.propose_change(Change::Guardians {
+            members: vec![accounts(1), accounts(2), accounts(3)],
+            threshold: 2,
+            approval_window: U64(WINDOW),
+        });
+        set_context(&"contract".parse().unwrap(), 0);
+        contract.execute_proposal(id);
+
+        let reserve: AccountId = "guardian".parse().unwrap();
+        contract.add_new_user(&reserve);
+        contract.stake_tickets_for(&reserve, near(5));
+        contract
+    }
+
+    fn near(amount: u128) -> u128 {
+        NearToken::from_near(amount).as_yoctonear()
+    }
+
+    // Times count from when the guardians of `setup` take over
+    fn set_context(account: &AccountId, timestamp_ms: u64) {
+        testing_env!(context(account)
+            .block_timestamp((TIMELOCK_DELAY + timestamp_ms) * 1_000_000)
+            .build());
+    }
+}
@@ -0,0 +1,265 @@
+use crate::journal::CallKind;
+use crate::*;
+use near_sdk::{near, require, serde_json::json};
+
+// Consecutive failures of an operation before the policy kicks in
+const FAILURE_THRESHOLD: u32 = 3;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthAction {
+    // Deposits and raffles pause, users can still leave
+    Degraded,
+    // Everything stops like with `emergency_start`, but users can still withdraw
+    Emergency,
+}
+
+// Operations whose failures the policy counts
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthKind {
+    Deposit,
+    Sweep,
+    Unstake,
+    Withdraw,
+    Migration,
+    InstantExit,
+    SweepIdle,
+    PrizeUpdate,
+}
+
+impl HealthKind {
+    // Queries and pings count for the operation that reads them
+    pub(crate) fn of(kind: &CallKind) -> Option<Self> {
+        match kind {
+            CallKind::Deposit => Some(Self::Deposit),
+            CallKind::Sweep => Some(Self::Sweep),
+            CallKind::Unstake => Some(Self::Unstake),
+            CallKind::Withdraw => Some(Self::Withdraw),
+            CallKind::Migration => Some(Self::Migration),
+            CallKind::InstantExit => Some(Self::InstantExit),
+            CallKind::SweepIdle => Some(Self::SweepIdle),
+            CallKind::Query | CallKind::Ping => None,
+        }
+    }
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct HealthPolicy {
+    pub failure_threshold: u32,
+    pub action: HealthAction,
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: FAILURE_THRESHOLD,
+            action: HealthAction::Degraded,
+        }
+    }
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct OperationHealth {
+    pub kind: HealthKind,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    // Block timestamps (ms), 0 if it never happened
+    pub last_success: u64,
+    pub last_failure: u64,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    pub degraded: bool,
+    pub operations: Vec<OperationHealth>,
+}
+
+#[near(serializers=[json])]
+pub struct HealthInfo {
+    pub degraded: bool,
+    pub paused: Paused,
+    pub policy: HealthPolicy,
+    pub operations: Vec<OperationHealth>,
+}
+
+#[near]
+impl Contract {
+    pub fn get_health(&self) -> HealthInfo {
+        HealthInfo {
+            degraded: self.health.degraded,
+            paused: self.config.paused.clone(),
+            policy: self.config.health_policy.clone(),
+            operations: self.health.operations.clone(),
+        }
+    }
+
+    pub fn set_health_policy(&mut self, failure_threshold: u32, action: HealthAction) {
+        self.change_config(
+            "set_health_policy",
+            ConfigPatch {
+                health_policy: Some(HealthPolicy {
+                    failure_threshold,
+                    action,
+                }),
+                ..Default::default()
+            },
+        );
+    }
+
+    // Leaves the degraded mode before the failing operations recover
+    pub fn clear_degraded(&mut self) {
+        self.require_role(&[Role::Guardian]);
+        require!(self.health.degraded, "The pool is not degraded");
+        self.health.degraded = false;
+        self.record_admin_action(
+            "clear_degraded",
+            json!({ "degraded": true }),
+            json!({ "degraded": false }),
+        );
+        self.log_health("health_recovered", None);
+    }
+
+    // Called with the result of every call to the validators
+    pub(crate) fn record_health(&mut self, kind: HealthKind, success: bool) {
+        let now = env::block_timestamp_ms();
+
+        let idx = match self.health.operations.iter().position(|op| op.kind == kind) {
+            Some(idx) => idx,
+            None => {
+                self.health.operations.push(OperationHealth {
+                    kind: kind.clone(),
+                    consecutive_failures: 0,
+                    total_failures: 0,
+                    last_success: 0,
+                    last_failure: 0,
+                });
+                self.health.operations.len() - 1
+            }
+        };
+
+        let operation = &mut self.health.operations[idx];
+        if success {
+            operation.consecutive_failures = 0;
+            operation.last_success = now;
+        } else {
+            operation.consecutive_failures += 1;
+            operation.total_failures += 1;
+            operation.last_failure = now;
+        }
+        let consecutive_failures = operation.consecutive_failures;
+
+        if consecutive_failures >= self.config.health_policy.failure_threshold {
+            self.apply_health_policy(kind);
+        } else if self.health.degraded && self.is_healthy() {
+            // Every operation works again
+            self.health.degraded = false;
+            self.log_health("health_recovered", None);
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        let threshold = self.config.health_policy.failure_threshold;
+        self.health
+            .operations
+            .iter()
+            .all(|op| op.consecutive_failures < threshold)
+    }
+
+    fn apply_health_policy(&mut self, kind: HealthKind) {
+        // A manual withdraw pause is kept
+        let emergency = Paused {
+            withdraw: self.config.paused.withdraw,
+            ..Paused::all()
+        };
+
+        match self.config.health_policy.action {
+            HealthAction::Degraded if !self.health.degraded => {
+                self.health.degraded = true;
+                self.log_health("health_degraded", Some(kind));
+            }
+            HealthAction::Emergency if self.config.paused != emergency => {
+                self.config.paused = emergency;
+                self.log_health("health_emergency", Some(kind));
+            }
+            _ => {}
+        }
+    }
+
+    fn log_health(&self, event: &str, kind: Option<HealthKind>) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": {
+                "failing": kind,
+                "operations": &self.health.operations,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_health_policy() {
+        set_context(1);
+        let mut contract = Contract::for_tests();
+
+        // Isolated failures are only counted
+        contract.record_health(HealthKind::Unstake, false);
+        contract.record_health(HealthKind::Unstake, true);
+        contract.record_health(HealthKind::PrizeUpdate, false);
+        contract.record_health(HealthKind::PrizeUpdate, false);
+        assert!(!contract.get_health().degraded);
+
+        set_context(2);
+        contract.record_health(HealthKind::PrizeUpdate, false);
+        let health = contract.get_health();
+        assert!(health.degraded);
+        assert_eq!(health.operations[0].total_failures, 1);
+        assert_eq!(health.operations[0].last_success, 1);
+        assert_eq!(health.operations[1].consecutive_failures, 3);
+        assert_eq!(health.operations[1].last_failure, 2);
+
+        // The pool recovers once the failing operation works again
+        contract.record_health(HealthKind::PrizeUpdate, true);
+        assert!(!contract.get_health().degraded);
+
+        contract.set_health_policy(1, HealthAction::Emergency);
+        contract.record_health(HealthKind::Withdraw, false);
+        // Users can still leave
+        assert_eq!(
+            contract.get_health().paused,
+            Paused {
+                withdraw: false,
+                ..Paused::all()
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "failure_threshold cannot be zero")]
+    fn test_health_policy_threshold() {
+        set_context(1);
+        let mut contract = Contract::for_tests();
+
+        contract.set_health_policy(0, HealthAction::Degraded);
+    }
+
+    fn set_context(timestamp_ms: u64) {
+        testing_env!(context(&"contract".parse().unwrap())
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
@@ -0,0 +1,230 @@
+use crate::journal::CallKind;
+use crate::lock::Operation;
+use crate::*;
+use near_sdk::{
+    near, require,
+    serde_json::{json, Value},
+    Gas, Promise, PromiseError,
+};
+
+// Room for the storage of the users that join later
+const STORAGE_RESERVE: NearToken = NearToken::from_near(1);
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum IdleTarget {
+    // Staked as tickets of the pool reserve
+    Restake,
+    // Staked and raffled with the next prize
+    Prize,
+}
+
+#[near]
+impl Contract {
+    // NEAR in our account that nobody can claim: yoctoNEAR leftovers,
+    // storage surplus, rewards sent to us, unclaimed dust...
+    pub fn get_idle_balance(&self) -> NearToken {
+        let storage_cost = env::storage_byte_cost().saturating_mul(env::storage_usage() as u128);
+
+        // Unstaked funds are still in the validators until a withdraw turn
+        let in_validators = self
+            .validators
+            .iter()
+            .fold(self.pool.to_unstake, |total, v| {
+                total.saturating_add(v.unstaking)
+            });
+        let withdrawn = self.pool.users_unstaked.saturating_sub(in_validators);
+
+        let reserved = storage_cost
+            .saturating_add(STORAGE_RESERVE)
+            .saturating_add(withdrawn)
+            .saturating_add(self.refunds.total)
+            .saturating_add(self.schedules.deposits)
+            .saturating_add(self.pool.queued_deposits)
+            .saturating_add(self.migrating_amount());
+
+        env::account_balance().saturating_sub(reserved)
+    }
+
+    pub fn sweep_idle(&mut self, target: IdleTarget) -> Promise {
+        self.require_role(&[Role::Guardian]);
+        self.require_not_paused(PauseFlag::External);
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(160)),
+            "Use at least 160Tgas"
+        );
+
+        // Funds coming back to us are only accounted for in their callbacks
+        let in_flight = self
+            .pool
+            .pending_deposits
+            .saturating_sub(self.pool.queued_deposits);
+        require!(
+            in_flight.is_zero()
+                && self.pool.instant_exits.is_zero()
+                && !self.is_running(&Operation::Withdraw)
+                && !self.is_running(&Operation::Migration),
+            "Funds in flight, try again later"
+        );
+
+        let amount = self.get_idle_balance();
+        require!(!amount.is_zero(), "No idle balance to sweep");
+        self.record_admin_action(
+            "sweep_idle",
+            Value::Null,
+            json!({ "target": &target, "amount": amount }),
+        );
+
+        let validator = self.validator_for_deposit(amount);
+        let entry_id = self.record_call(CallKind::SweepIdle, &validator, amount);
+
+        Promise::new(validator.clone())
+            .function_call(
+                "deposit_and_stake".to_string(),
+                NO_ARGS,
+                amount,
+                Gas::from_tgas(120),
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "sweep_idle_callback".to_string(),
+                    json!({
+                        "target": target,
+                        "amount": amount,
+                        "validator": validator,
+                        "entry_id": entry_id,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(30),
+                ),
+            )
+    }
+
+    #[private]
+    pub fn sweep_idle_callback(
+        &mut self,
+        target: IdleTarget,
+        amount: NearToken,
+        validator: AccountId,
+        entry_id: u64,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
+
+        if call_result.is_err() {
+            // The funds came back and stay idle
+            log!("Failed to stake the idle balance");
+            return false;
+        }
+
+        match target {
+            IdleTarget::Restake => {
+                let guardian = self.config.guardian.clone();
+                if !self.is_registered(&guardian) {
+                    self.add_new_user(&guardian);
+                }
+                self.stake_tickets_for(&guardian, amount.as_yoctonear());
+
+                let idx = self.validator_index(&validator);
+                self.validators[idx].staked = self.validators[idx].staked.saturating_add(amount);
+                self.pool.tickets = self.pool.tickets.saturating_add(amount);
+            }
+            // Staked funds above the tickets are the prize, the next update counts them too
+            IdleTarget::Prize => {
+                self.pool.prize = self
+                    .pool
+                    .prize
+                    .saturating_add(amount)
+                    .min(self.config.max_to_raffle);
+            }
+        }
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "sweep_idle",
+            "data": {
+                "target": &target,
+                "amount": &amount,
+                "validator": &validator,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_idle_balance() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&guardian, NearToken::from_near(10));
+        let mut contract = Contract::for_tests();
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        let idle = |contract: &Contract| {
+            let storage_cost =
+                env::storage_byte_cost().saturating_mul(env::storage_usage() as u128);
+            near(10)
+                .saturating_sub(STORAGE_RESERVE)
+                .saturating_sub(storage_cost)
+                .saturating_sub(contract.get_idle_balance())
+        };
+
+        // Funds of the users are never idle, wherever they are
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, near(5).as_yoctonear());
+        contract.unstake_tickets_for(&user, near(3));
+        contract.pool.to_unstake = near(1);
+        contract.validators[0].unstaking = near(1);
+        contract.refunds.total = near(2);
+        assert_eq!(idle(&contract), near(3));
+
+        assert_eq!(contract.withdraw_all_for(&user), near(3).as_yoctonear());
+        assert_eq!(contract.pool.users_unstaked, near(0));
+
+        // Restaked funds back the pool reserve
+        contract.sweep_idle(IdleTarget::Restake);
+        assert!(contract.sweep_idle_callback(IdleTarget::Restake, near(4), accounts(0), 0, Ok(())));
+        assert_eq!(contract.get_staked_for(&guardian), near(4).as_yoctonear());
+        assert_eq!(contract.validators[0].staked, near(4));
+
+        let entry_id = contract.record_call(CallKind::SweepIdle, &accounts(0), near(1));
+        assert!(contract.sweep_idle_callback(
+            IdleTarget::Prize,
+            near(1),
+            accounts(0),
+            entry_id,
+            Ok(())
+        ));
+        assert_eq!(contract.pool.prize, near(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires the Guardian role")]
+    fn test_sweep_idle_guardian_only() {
+        set_context(&"guardian".parse().unwrap(), NearToken::from_near(10));
+        let mut contract = Contract::for_tests();
+
+        set_context(&"user".parse().unwrap(), NearToken::from_near(10));
+        contract.sweep_idle(IdleTarget::Prize);
+    }
+
+    fn set_context(account: &AccountId, balance: NearToken) {
+        testing_env!(context(account).account_balance(balance).build());
+    }
+}
@@ -0,0 +1,279 @@
+use crate::health::HealthKind;
+use crate::pool::ExternalUser;
+use crate::*;
+use near_sdk::{serde_json, serde_json::json, store::LookupMap, Gas, Promise, PromiseResult};
+
+// Completed entries older than the last calls are dropped, pending
+// entries stay until their callback arrives
+const MAX_JOURNAL_ENTRIES: u64 = 500;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallKind {
+    Deposit,
+    Sweep,
+    Unstake,
+    Withdraw,
+    Migration,
+    InstantExit,
+    SweepIdle,
+    // Balance queries and pings, the operation reading them tracks their health
+    Query,
+    Ping,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub kind: CallKind,
+    // External pool that receives the call
+    pub validator: AccountId,
+    pub amount: NearToken,
+    pub status: CallStatus,
+    // Block timestamps (ms) of the call and its callback
+    pub started_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+#[near(serializers=[borsh])]
+pub struct Journal {
+    pub next_id: u64,
+    pub entries: LookupMap<u64, JournalEntry>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            entries: LookupMap::new(StorageKey::Journal),
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_journal_entry(&self, id: u64) -> Option<JournalEntry> {
+        self.journal.entries.get(&id).cloned()
+    }
+
+    // Entries with `from <= id < until` that were not dropped, at most
+    // `MAX_JOURNAL_ENTRIES` ids per call
+    pub fn get_journal(&self, from: u64, until: u64) -> Vec<JournalEntry> {
+        let until = until
+            .min(self.journal.next_id)
+            .min(from.saturating_add(MAX_JOURNAL_ENTRIES));
+
+        (from..until)
+            .filter_map(|id| self.get_journal_entry(id))
+            .collect()
+    }
+
+    // Every call to an external pool is recorded before it is sent,
+    // its callback receives the entry id to complete it
+    pub(crate) fn record_call(
+        &mut self,
+        kind: CallKind,
+        validator: &AccountId,
+        amount: NearToken,
+    ) -> u64 {
+        let id = self.journal.next_id;
+        self.journal.next_id += 1;
+
+        let entry = JournalEntry {
+            id,
+            kind,
+            validator: validator.clone(),
+            amount,
+            status: CallStatus::Pending,
+            started_at: env::block_timestamp_ms(),
+            completed_at: None,
+        };
+
+        self.journal.entries.insert(id, entry);
+
+        // Callbacks return early without their entry, so a pending one is never dropped
+        if let Some(old_id) = id.checked_sub(MAX_JOURNAL_ENTRIES) {
+            if !self.is_pending_call(old_id) {
+                self.journal.entries.remove(&old_id);
+            }
+        }
+
+        id
+    }
+
+    // Returns false if the entry was already completed, so callbacks
+    // never apply the same result twice
+    pub(crate) fn complete_call(&mut self, id: u64, success: bool) -> bool {
+        let Some(mut entry) = self.get_journal_entry(id) else {
+            log!("Unknown journal entry {}", id);
+            return false;
+        };
+
+        if entry.status != CallStatus::Pending {
+            log!("Journal entry {} was already completed", id);
+            return false;
+        }
+
+        entry.status = match success {
+            true => CallStatus::Succeeded,
+            false => CallStatus::Failed,
+        };
+        entry.completed_at = Some(env::block_timestamp_ms());
+        if let Some(kind) = HealthKind::of(&entry.kind) {
+            self.record_health(kind, success);
+        }
+
+        // Pending entries outlive the journal size, they are dropped once completed
+        match id + MAX_JOURNAL_ENTRIES < self.journal.next_id {
+            true => self.journal.entries.remove(&id),
+            false => self.journal.entries.insert(id, entry),
+        };
+
+        true
+    }
+
+    // Asks each validator for our account in parallel, the callback reads
+    // the answers with `read_accounts`
+    pub(crate) fn query_accounts(
+        &mut self,
+        validators: &[AccountId],
+        gas: Gas,
+    ) -> (Vec<u64>, Promise) {
+        let args = json!({ "account_id": env::current_account_id() })
+            .to_string()
+            .into_bytes();
+
+        let (entries, calls): (Vec<u64>, Vec<Promise>) = validators
+            .iter()
+            .map(|validator| {
+                let entry_id = self.record_call(CallKind::Query, validator, NO_DEPOSIT);
+                let call = Promise::new(validator.clone()).function_call(
+                    "get_account".to_string(),
+                    args.clone(),
+                    NO_DEPOSIT,
+                    gas,
+                );
+
+                (entry_id, call)
+            })
+            .unzip();
+
+        let calls = calls
+            .into_iter()
+            .reduce(|joint, call| joint.and(call))
+            .expect("No validator to query");
+
+        (entries, calls)
+    }
+
+    // Answers of `query_accounts`, in the order the validators were queried
+    pub(crate) fn read_accounts(&mut self, entries: &[u64]) -> Vec<Option<ExternalUser>> {
+        entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry_id)| {
+                let account = match env::promise_result(idx as u64) {
+                    PromiseResult::Successful(data) => {
+                        serde_json::from_slice::<ExternalUser>(&data).ok()
+                    }
+                    PromiseResult::Failed => None,
+                };
+                self.complete_call(*entry_id, account.is_some());
+
+                account
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_pending_call(&self, id: u64) -> bool {
+        self.get_journal_entry(id)
+            .is_some_and(|entry| entry.status == CallStatus::Pending)
+    }
+
+    pub(crate) fn is_succeeded_call(&self, id: u64) -> bool {
+        self.get_journal_entry(id)
+            .is_some_and(|entry| entry.status == CallStatus::Succeeded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{testing_env, PromiseError};
+
+    #[test]
+    fn test_journal() {
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        let id = contract.record_call(CallKind::Unstake, &accounts(0), near(1));
+        assert!(contract.is_pending_call(id));
+
+        // A callback completes its entry only once
+        assert!(contract.complete_call(id, true));
+        assert!(!contract.complete_call(id, false));
+        assert!(contract.is_succeeded_call(id));
+        assert!(!contract.complete_call(id + 1, true));
+
+        // Completed entries are dropped by the newer ones
+        for _ in 0..MAX_JOURNAL_ENTRIES {
+            contract.record_call(CallKind::Deposit, &accounts(1), near(1));
+        }
+        assert!(contract.get_journal_entry(id).is_none());
+        assert!(!contract.complete_call(id, true));
+
+        // Pending ones wait for their callback
+        contract.record_call(CallKind::Deposit, &accounts(1), near(1));
+        assert!(contract.is_pending_call(1));
+        assert!(contract.complete_call(1, true));
+        assert!(contract.get_journal_entry(1).is_none());
+
+        let journal = contract.get_journal(0, 3);
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal[0].id, 2);
+        assert_eq!(journal[0].kind, CallKind::Deposit);
+    }
+
+    #[test]
+    fn test_queries_are_journaled() {
+        set_context();
+        let mut contract = Contract::for_tests();
+        contract.config.ping_before_prize = true;
+
+        testing_env!(context(&accounts(1))
+            .block_timestamp(3_600_000_000_000)
+            .build());
+        contract.update_prize();
+
+        let journal = contract.get_journal(0, 10);
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal[0].kind, CallKind::Query);
+        assert_eq!(journal[1].kind, CallKind::Ping);
+
+        // The pings complete on their own, the queries in the prize callback
+        contract.ping_callback(1, Err(PromiseError::Failed));
+        assert_eq!(
+            contract.get_journal_entry(1).unwrap().status,
+            CallStatus::Failed
+        );
+        assert!(contract.is_pending_call(0));
+        assert!(contract.health.operations.is_empty());
+    }
+
+    fn set_context() {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+    }
+}
@@ -1,9 +1,28 @@
 // Find all our documentation at https://docs.near.org
+// `#[near]` generates a `new` with all init arguments for the contract's `Ext`
+#![allow(clippy::too_many_arguments)]
 use near_sdk::{
-    env, json_types::U64, log, near, store::Vector, AccountId, NearToken, PanicOnDefault,
+    env, json_types::U64, log, near, serde_json::json, store::LookupSet, store::Vector, AccountId,
+    BorshStorageKey, NearToken, PanicOnDefault,
 };
+use audit::AuditLog;
+use config::ConfigPatch;
+use deposits::Deposits;
+use guardians::{CriticalAction, Guardians};
+use health::{Health, HealthPolicy};
+use journal::Journal;
+use migration::Migration;
+use pause::{PauseFlag, Paused};
 use pool::Pool;
+use reconcile::Reconciliation;
+use refunds::Refunds;
+use roles::{Role, Roles};
+use schedule::Schedules;
+use shortfall::Shortfall;
+use timelock::{Change, Timelock};
+use upgrade::StateUpgrade;
 use users::Users;
+use validators::Validator;
 
 pub const NO_ARGS: Vec<u8> = vec![];
 pub const NO_DEPOSIT: NearToken = NearToken::from_near(0);
@@ -11,6 +30,9 @@ pub const NO_DEPOSIT: NearToken = NearToken::from_near(0);
 // The raffle happens once per day (expressed in ms)
 const RAFFLE_WAIT: U64 = U64(86400000);
 
+// Proposed changes wait a day before they can be executed (expressed in ms)
+const TIMELOCK_DELAY: u64 = 86400000;
+
 // The users cannot have more than a certain amount of NEARs,
 // to limit whale's size in the pool. Default: A thousand NEARs
 const MAX_DEPOSIT: NearToken = NearToken::from_near(1000);
@@ -28,11 +50,56 @@ const MIN_TO_RAFFLE: NearToken = NearToken::from_millinear(100);
 // Maximum amount to Raffle (50 NEAR)
 const MAX_TO_RAFFLE: NearToken = NearToken::from_near(100);
 
+// Staking pools round shares, so tiny differences are noise (1 milliNEAR)
+const ROUNDING_TOLERANCE: NearToken = NearToken::from_millinear(1);
+
+pub mod audit;
+pub mod config;
+pub mod dao;
+pub mod deposits;
 pub mod external;
+pub mod guardians;
+pub mod health;
+pub mod idle;
+pub mod journal;
+pub mod liquid;
+pub mod lock;
+pub mod migration;
+pub mod pause;
 pub mod pool;
+pub mod reconcile;
+pub mod refunds;
+pub mod roles;
+pub mod schedule;
+pub mod shortfall;
+#[cfg(test)]
+mod test_utils;
+pub mod timelock;
+pub mod upgrade;
 pub mod users;
+pub mod validators;
+pub mod whitelist;
+
+#[near(serializers = [borsh])]
+#[derive(BorshStorageKey)]
+pub(crate) enum StorageKey {
+    Users,
+    Tree,
+    Schedules,
+    Refunds,
+    QueuedDeposits,
+    SweepingDeposits,
+    Journal,
+    Roles,
+    AuditLog,
+    DaoProposals,
+    SchedulesByUser,
+    SchedulesByTime,
+    SchedulesByRaffle,
+}
 
-#[near(serializers=[borsh])]
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Action {
     Unstake,
     Withdraw,
@@ -43,22 +110,66 @@ pub enum Action {
 pub struct UserInfo {
     pub staked: NearToken,
     pub unstaked: NearToken,
+    pub pending_deposit: NearToken,
+    pub available: bool,
+    // Epochs left until the unstaked funds can be withdrawn
+    pub withdraw_turn: u64,
+}
+
+#[near(serializers=[json])]
+#[derive(Clone)]
+pub struct WithdrawalStatus {
+    pub unstaked: NearToken,
+    pub withdraw_turn: Option<u64>,
+    pub next_withdraw_turn: u64,
+    pub next_withdraw_epoch: u64,
+    pub current_epoch: u64,
+    pub next_action: Action,
     pub available: bool,
-    pub withdraw_turn: u8,
+    pub epochs_remaining: u64,
 }
 
 #[near(serializers=[borsh, json])]
 #[derive(Clone)]
 pub struct Config {
-    external_pool: AccountId,
     min_to_raffle: NearToken,
     max_to_raffle: NearToken,
     min_deposit: NearToken,
     max_deposit: NearToken,
     epochs_wait: u64,
     time_between_raffles: u64,
+    // Holds the pool reserve, it starts as the only guardian
     guardian: AccountId,
-    pub emergency: bool,
+    // Staking pool whitelist, like the one used by the lockup contracts
+    whitelist: Option<AccountId>,
+    // DAO contract whose approved proposals apply without the timelock
+    dao: Option<AccountId>,
+    batch_deposits: bool,
+    ping_before_prize: bool,
+    prize_gas: PrizeGas,
+    health_policy: HealthPolicy,
+    // Sensitive changes wait this long (ms) between proposal and execution
+    timelock_delay: u64,
+    pub paused: Paused,
+}
+
+// Gas (in Tgas) of each step of `update_prize`, per validator except the callback
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct PrizeGas {
+    pub ping: u64,
+    pub get_account: u64,
+    pub callback: u64,
+}
+
+impl Default for PrizeGas {
+    fn default() -> Self {
+        Self {
+            ping: 15,
+            get_account: 15,
+            callback: 40,
+        }
+    }
 }
 
 // Define the contract structure
@@ -66,8 +177,25 @@ pub struct Config {
 #[derive(PanicOnDefault)]
 pub struct Contract {
     config: Config,
+    roles: Roles,
+    guardians: Guardians,
+    timelock: Timelock,
+    audit: AuditLog,
+    // Executed proposals of each DAO, their ids restart with a new DAO
+    dao_proposals: LookupSet<(AccountId, u64)>,
     pool: Pool,
     users: Users,
+    schedules: Schedules,
+    refunds: Refunds,
+    deposits: Deposits,
+    journal: Journal,
+    health: Health,
+    validators: Vec<Validator>,
+    migration: Option<Migration>,
+    shortfall: Option<Shortfall>,
+    reconciliation: Option<Reconciliation>,
+    // Users still in the layout of the code before the last upgrade
+    upgrade: Option<StateUpgrade>,
     next_action: Action,
 }
 
@@ -86,23 +214,52 @@ impl Contract {
         max_deposit: Option<NearToken>,
         epochs_wait: Option<u64>,
         time_between_raffles: Option<U64>,
+        whitelist: Option<AccountId>,
+        timelock_delay: Option<U64>,
     ) -> Self {
-        Self {
+        let mut contract = Self {
             config: Config {
-                external_pool,
-                guardian,
+                guardian: guardian.clone(),
+                whitelist,
+                dao: None,
                 max_to_raffle: max_to_raffle.unwrap_or(MAX_TO_RAFFLE),
                 min_to_raffle: min_to_raffle.unwrap_or(MIN_TO_RAFFLE),
                 min_deposit: min_deposit.unwrap_or(MIN_DEPOSIT),
                 max_deposit: max_deposit.unwrap_or(MAX_DEPOSIT),
                 epochs_wait: epochs_wait.unwrap_or(EPOCHS_WAIT),
                 time_between_raffles: time_between_raffles.unwrap_or(RAFFLE_WAIT).0,
-                emergency: false,
+                batch_deposits: false,
+                ping_before_prize: false,
+                prize_gas: PrizeGas::default(),
+                health_policy: HealthPolicy::default(),
+                timelock_delay: timelock_delay.map_or(TIMELOCK_DELAY, |delay| delay.0),
+                paused: Paused::default(),
             },
+            // The contract account administers the pool until it transfers the ownership
+            roles: Roles::new(env::current_account_id()),
+            guardians: Guardians::new(guardian.clone()),
+            timelock: Timelock::default(),
+            audit: AuditLog::default(),
+            dao_proposals: LookupSet::new(StorageKey::DaoProposals),
             pool: Pool::new(first_raffle.0),
             users: Users::default(),
+            schedules: Schedules::default(),
+            refunds: Refunds::default(),
+            deposits: Deposits::default(),
+            journal: Journal::default(),
+            health: Health::default(),
+            validators: vec![],
+            migration: None,
+            shortfall: None,
+            reconciliation: None,
+            upgrade: None,
             next_action: Action::Unstake,
-        }
+        };
+
+        contract.roles.members.insert(guardian, vec![Role::Guardian]);
+        contract.add_unverified_validator(external_pool, 1);
+        contract.validate_config();
+        contract
     }
 
     pub fn get_config(&self) -> Config {
@@ -137,23 +294,21 @@ impl Contract {
                 let user_node = self.users.tree[uid].clone(); 
                 
                 let staked = user_node.staked;
-                let when = user.withdraw_turn.unwrap_or(0);
-                let now = self.pool.next_withdraw_epoch - 1;
-
-                let remaining = if when > now { when - now } else { 0 };
-                let available = user.unstaked > 0 && now >= when;
+                let (available, remaining) = self.withdrawal_wait(user.unstaked, user.withdraw_turn);
 
                 UserInfo {
                     staked: NearToken::from_yoctonear(staked),
                     unstaked: NearToken::from_yoctonear(user.unstaked),
+                    pending_deposit: NearToken::from_yoctonear(user.pending_deposit),
                     available,
-                    withdraw_turn: remaining as u8,
+                    withdraw_turn: remaining,
                 }
             },
             None => {
                 UserInfo {
                     staked: NearToken::from_yoctonear(0),
                     unstaked: NearToken::from_yoctonear(0),
+                    pending_deposit: NearToken::from_yoctonear(0),
                     available: false,
                     withdraw_turn: 0,
                 }
@@ -161,23 +316,97 @@ impl Contract {
         }
     }
 
-    #[private]
-    pub fn emergency_stop(&mut self) {
-        self.config.emergency = false;
+    pub fn get_withdrawal_status(&self, user: AccountId) -> WithdrawalStatus {
+        let current_epoch = env::epoch_height();
+        let next_withdraw_turn = self.pool.next_withdraw_turn;
+        let next_withdraw_epoch = self.pool.next_withdraw_epoch;
+
+        let (unstaked, withdraw_turn) = match self.users.map.get(&user) {
+            Some(user) => (user.unstaked, user.withdraw_turn),
+            None => (0, None),
+        };
+
+        let (available, epochs_remaining) = self.withdrawal_wait(unstaked, withdraw_turn);
+
+        WithdrawalStatus {
+            unstaked: NearToken::from_yoctonear(unstaked),
+            withdraw_turn,
+            next_withdraw_turn,
+            next_withdraw_epoch,
+            current_epoch,
+            next_action: self.next_action.clone(),
+            available,
+            epochs_remaining,
+        }
     }
 
-    #[private]
+    // Whether the user's unstaked funds are back in the contract and how many
+    // epochs they still wait. Shared by the views and `withdraw_all`
+    pub(crate) fn withdrawal_wait(&self, unstaked: u128, withdraw_turn: Option<u64>) -> (bool, u64) {
+        let next_withdraw_turn = self.pool.next_withdraw_turn;
+        let until_withdraw = self.pool.next_withdraw_epoch.saturating_sub(env::epoch_height());
+
+        match withdraw_turn {
+            _ if unstaked == 0 => (false, 0),
+            // `unstake_external` opens the turn after the user's and
+            // `finish_withdraw_turn` moves past it once the funds are back
+            Some(turn) if next_withdraw_turn > turn + 1 => (true, 0),
+            // Already unstaked from the external pool, waiting for the epochs to pass
+            Some(turn) if next_withdraw_turn > turn => (false, until_withdraw),
+            // Waiting for the next unstake from the external pool, which
+            // happens only after the ongoing withdraw (if any) completes
+            _ => match self.next_action {
+                Action::Unstake => (false, self.config.epochs_wait),
+                Action::Withdraw => (false, until_withdraw + self.config.epochs_wait),
+            },
+        }
+    }
+
+    // Resuming needs the guardians' approval, returns the id of the action
+    pub fn emergency_stop(&mut self) -> u64 {
+        self.propose_action(CriticalAction::Resume(PauseFlag::all()))
+    }
+
+    // Pauses everything, `pause` stops single operations
     pub fn emergency_start(&mut self) {
-        self.config.emergency = true;
+        self.require_role(&[Role::Pauser, Role::Guardian]);
+        self.set_paused("emergency_start", Paused::all());
     }
 
-    #[private]
+    // The setters below are shortcuts for `update_config`
     pub fn set_time_between_raffles(&mut self, time: U64) {
-        self.config.time_between_raffles = time.0;
+        self.change_config("set_time_between_raffles", ConfigPatch {
+            time_between_raffles: Some(time),
+            ..Default::default()
+        });
     }
 
-    #[private]
     pub fn set_epochs_wait(&mut self, epochs: u64) {
-        self.config.epochs_wait = epochs;
+        self.change_config("set_epochs_wait", ConfigPatch {
+            epochs_wait: Some(epochs),
+            ..Default::default()
+        });
+    }
+
+    pub fn set_batch_deposits(&mut self, enabled: bool) {
+        self.change_config("set_batch_deposits", ConfigPatch {
+            batch_deposits: Some(enabled),
+            ..Default::default()
+        });
+    }
+
+    // Standard staking pools only add the rewards to our balance after a `ping`
+    pub fn set_ping_before_prize(&mut self, enabled: bool) {
+        self.change_config("set_ping_before_prize", ConfigPatch {
+            ping_before_prize: Some(enabled),
+            ..Default::default()
+        });
+    }
+
+    pub fn set_prize_gas(&mut self, ping: u64, get_account: u64, callback: u64) {
+        self.change_config("set_prize_gas", ConfigPatch {
+            prize_gas: Some(PrizeGas { ping, get_account, callback }),
+            ..Default::default()
+        });
     }
 }
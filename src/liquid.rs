@@ -0,0 +1,330 @@
+use crate::journal::CallKind;
+use crate::lock::Operation;
+use crate::*;
+use near_sdk::{json_types::U128, near, require, serde_json::json, Gas, Promise, PromiseError};
+
+// Fees are expressed in basis points
+const MAX_FEE_BPS: u32 = 10_000;
+
+#[near]
+impl Contract {
+    // A liquid staking contract behaves like a staking pool for deposits,
+    // unstakes and `get_account`, whose `staked_balance` values our shares at
+    // the current price. It also lets us swap the shares back to NEAR instantly
+    // Timelocked, returns the id of the proposal
+    pub fn set_liquid_validator(&mut self, account_id: Option<AccountId>) -> u64 {
+        self.propose_change(Change::LiquidValidator(account_id))
+    }
+
+    pub(crate) fn apply_liquid_validator(&mut self, account_id: Option<AccountId>) {
+        if let Some(account_id) = &account_id {
+            self.validator_index(account_id);
+        }
+
+        for validator in self.validators.iter_mut() {
+            validator.liquid = Some(&validator.account_id) == account_id.as_ref();
+        }
+    }
+
+    // Users who accept the swap fee of the liquid staking contract can skip the unbonding period
+    pub fn instant_exit(&mut self, amount: NearToken, max_fee_bps: u32) -> Promise {
+        let user = env::predecessor_account_id();
+
+        self.require_not_paused(PauseFlag::Unstake);
+        require!(
+            self.shortfall.is_none(),
+            "The pool is absorbing a loss, try again later"
+        );
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(100)),
+            "Use at least 100Tgas"
+        );
+        require!(max_fee_bps <= MAX_FEE_BPS, "Invalid fee");
+        require!(self.is_registered(&user), "User not registered in the pool");
+        require!(
+            user != self.config.guardian,
+            "Reserve withdrawals need the guardians' approval"
+        );
+
+        let user_tickets = self.get_staked_for(&user);
+        require!(
+            amount.as_yoctonear() <= user_tickets,
+            format!("Amount cant exceed {}", user_tickets)
+        );
+
+        // Same rule as `unstake`, users cannot keep less than the minimum deposit
+        let mut amount = amount;
+        if user_tickets - amount.as_yoctonear() < self.config.min_deposit.as_yoctonear() {
+            amount = NearToken::from_yoctonear(user_tickets);
+        }
+
+        let idx = self
+            .validators
+            .iter()
+            .position(|validator| validator.liquid)
+            .expect("No liquid staking available");
+        let liquid = self.validators[idx].clone();
+
+        require!(liquid.verified, "The liquid staking is not verified yet");
+        require!(
+            !self.is_migrating_from(&liquid.account_id),
+            "The liquid staking is being migrated"
+        );
+        require!(
+            liquid.staked >= amount,
+            "Not enough liquid stake for an instant exit"
+        );
+
+        // The prize and the unstake turns read the stake we are about to move
+        let lock_id = self.start_interacting(Operation::InstantExit);
+
+        // The tickets leave the raffle now, and come back if the swap fails
+        self.remove_tickets_from(&user, amount.as_yoctonear());
+        self.pool.instant_exits = self.pool.instant_exits.saturating_add(amount);
+
+        let entry_id = self.record_call(CallKind::InstantExit, &liquid.account_id, amount);
+        let min_amount_out =
+            amount.as_yoctonear() / MAX_FEE_BPS as u128 * (MAX_FEE_BPS - max_fee_bps) as u128;
+
+        Promise::new(liquid.account_id.clone())
+            .function_call(
+                "instant_unstake".to_string(),
+                json!({ "amount": U128(amount.as_yoctonear()), "min_amount_out": U128(min_amount_out) })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(50),
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "instant_exit_callback".to_string(),
+                    json!({
+                        "user": user,
+                        "amount": amount,
+                        "validator": liquid.account_id,
+                        "entry_id": entry_id,
+                        "lock_id": lock_id,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(30),
+                ),
+            )
+    }
+
+    #[private]
+    pub fn instant_exit_callback(
+        &mut self,
+        user: AccountId,
+        amount: NearToken,
+        validator: AccountId,
+        entry_id: u64,
+        lock_id: u64,
+        #[callback_result] call_result: Result<U128, PromiseError>,
+    ) -> bool {
+        self.stop_interacting(lock_id);
+
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
+
+        self.pool.instant_exits = self.pool.instant_exits.saturating_sub(amount);
+
+        let Ok(received) = call_result else {
+            // The shares were not swapped, the user keeps the tickets
+            log!("Failed to exit through the liquid staking, restoring tickets");
+            self.stake_tickets_for(&user, amount.as_yoctonear());
+            return false;
+        };
+
+        let received = NearToken::from_yoctonear(received.0);
+
+        let idx = self.validator_index(&validator);
+        self.validators[idx].staked = self.validators[idx].staked.saturating_sub(amount);
+        self.pool.tickets = self.pool.tickets.saturating_sub(amount);
+
+        // The swap fee is paid by the user, who receives what the swap returned
+        self.transfer_to(&user, received);
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "instant_exit",
+            "data": {
+                "user": &user,
+                "amount": &amount,
+                "received": &received,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::CallStatus;
+    use crate::pool::ExternalUser;
+    use crate::test_utils::{context, set_context};
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{serde_json, test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    #[test]
+    fn test_instant_exit() {
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+        contract.apply_liquid_validator(Some(accounts(1)));
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, near(6).as_yoctonear());
+        contract.validators[0].staked = near(3);
+        contract.validators[1].staked = near(3);
+        contract.pool.tickets = near(6);
+
+        // A failed swap gives the tickets back
+        set_context(&user);
+        contract.instant_exit(near(2), 50);
+        assert_eq!(contract.get_staked_for(&user), near(4).as_yoctonear());
+        assert_eq!(contract.pool.instant_exits, near(2));
+
+        set_context(&"contract".parse().unwrap());
+        contract.instant_exit_callback(
+            user.clone(),
+            near(2),
+            accounts(1),
+            0,
+            0,
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(contract.get_staked_for(&user), near(6).as_yoctonear());
+        assert_eq!(contract.pool.instant_exits, near(0));
+
+        // A successful swap leaves the pool right away
+        set_context(&user);
+        contract.instant_exit(near(2), 50);
+        set_context(&"contract".parse().unwrap());
+        let received = U128(NearToken::from_millinear(1990).as_yoctonear());
+        assert!(contract.instant_exit_callback(
+            user.clone(),
+            near(2),
+            accounts(1),
+            1,
+            1,
+            Ok(received)
+        ));
+        assert_eq!(contract.get_staked_for(&user), near(4).as_yoctonear());
+        assert_eq!(contract.pool.tickets, near(4));
+        assert_eq!(contract.validators[1].staked, near(1));
+        assert_eq!(contract.validators[0].staked, near(3));
+    }
+
+    #[test]
+    fn test_reconcile_during_instant_exit() {
+        let user: AccountId = "user".parse().unwrap();
+        let guardian: AccountId = "guardian".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+        contract.apply_liquid_validator(Some(accounts(1)));
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, near(6).as_yoctonear());
+        contract.validators[0].staked = near(3);
+        contract.validators[1].staked = near(3);
+        // One ticket too many, the guardian will fix it
+        contract.pool.tickets = near(7);
+
+        set_context(&user);
+        contract.instant_exit(near(2), 50);
+
+        set_context(&guardian);
+        contract.reconcile(true);
+
+        // The validators still hold the exiting stake
+        let account = |idx: usize, staked: u128| {
+            PromiseResult::Successful(
+                serde_json::to_vec(&ExternalUser {
+                    account_id: accounts(idx),
+                    unstaked_balance: near(0),
+                    staked_balance: near(staked),
+                    can_withdraw: true,
+                })
+                .unwrap(),
+            )
+        };
+        let reconcile_callback = |contract: &mut Contract, staked: [u128; 2]| {
+            testing_env!(
+                context(&"contract".parse().unwrap()).build(),
+                test_vm_config(),
+                RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![account(0, staked[0]), account(1, staked[1])],
+            );
+            let entries = contract
+                .get_journal(0, 10)
+                .iter()
+                .filter(|entry| {
+                    entry.kind == CallKind::Query && entry.status == CallStatus::Pending
+                })
+                .map(|entry| entry.id)
+                .collect();
+            contract
+                .reconcile_callback(vec![accounts(0), accounts(1)], true, entries)
+                .unwrap()
+        };
+
+        // Rebuilding the tickets now would miss the exit in flight
+        let reconciliation = reconcile_callback(&mut contract, [3, 3]);
+        assert!(!reconciliation.discrepancies.is_empty());
+        assert!(!reconciliation.fixed);
+        assert_eq!(contract.pool.tickets, near(7));
+
+        set_context(&"contract".parse().unwrap());
+        let received = U128(NearToken::from_millinear(1990).as_yoctonear());
+        assert!(contract.instant_exit_callback(
+            user.clone(),
+            near(2),
+            accounts(1),
+            0,
+            0,
+            Ok(received)
+        ));
+        assert_eq!(contract.pool.tickets, near(5));
+
+        // Once the swap resolved the tickets follow the users' stake
+        set_context(&guardian);
+        contract.reconcile(true);
+        let reconciliation = reconcile_callback(&mut contract, [3, 1]);
+        assert!(reconciliation.fixed);
+        assert_eq!(contract.pool.tickets, near(4));
+        assert_eq!(contract.validators[0].staked, near(3));
+        assert_eq!(contract.validators[1].staked, near(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough liquid stake for an instant exit")]
+    fn test_instant_exit_needs_liquid_stake() {
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.apply_liquid_validator(Some(accounts(0)));
+
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, NearToken::from_near(5).as_yoctonear());
+        contract.validators[0].staked = NearToken::from_near(1);
+
+        set_context(&user);
+        contract.instant_exit(NearToken::from_near(2), 50);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::pool::ExternalUser;
+use crate::*;
+use near_sdk::{near, require, serde_json, serde_json::json, Gas, Promise};
+
+// Callbacks resolve within a few blocks, a lock older than this is stuck (1 hour in ms)
+const LOCK_TIMEOUT: u64 = 3_600_000;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    UpdatePrize,
+    Unstake,
+    Withdraw,
+    Migration,
+    Sweep,
+    InstantExit,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct InteractionLock {
+    // Passed to the callbacks, they only release the lock they were started with
+    pub id: u64,
+    // Account that started the operation
+    pub owner: AccountId,
+    // Block timestamp (ms) when the lock was taken
+    pub since: u64,
+    pub operation: Operation,
+}
+
+impl Operation {
+    // Operations that cannot run at the same time, because one of them reads
+    // state the other one is changing or both share the turn counters
+    fn conflicts_with(&self, other: &Operation) -> bool {
+        use Operation::*;
+
+        match (self, other) {
+            // The prize compares the validators' balance against the tickets
+            (UpdatePrize, Unstake | Migration | Sweep)
+            | (Unstake | Migration | Sweep, UpdatePrize) => true,
+            // Unstake and withdraw turns share `turn_calls` and `next_action`
+            (Unstake, Withdraw) | (Withdraw, Unstake) => true,
+            // Unstakes must not draw from the validator being migrated
+            (Unstake, Migration) | (Migration, Unstake) => true,
+            // The liquid validator keeps the exiting stake until the swap resolves
+            (InstantExit, UpdatePrize | Unstake | Migration)
+            | (UpdatePrize | Unstake | Migration, InstantExit) => true,
+            _ => self == other,
+        }
+    }
+}
+
+impl InteractionLock {
+    fn is_expired(&self) -> bool {
+        env::block_timestamp_ms() >= self.since + LOCK_TIMEOUT
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_locks(&self) -> Vec<InteractionLock> {
+        self.pool.locks.clone()
+    }
+
+    // The guardian can release a stuck lock, once the validators confirm
+    // they hold what we think they hold
+    pub fn recover_lock(&mut self, operation: Operation) -> Promise {
+        self.require_role(&[Role::Guardian]);
+        let lock_id = self
+            .pool
+            .locks
+            .iter()
+            .find(|lock| lock.operation == operation)
+            .map(|lock| lock.id)
+            .expect("Nothing to recover");
+        self.record_admin_action(
+            "recover_lock",
+            serde_json::Value::Null,
+            json!({ "operation": &operation }),
+        );
+
+        let min_gas = 30 + 15 * self.validators.len() as u64;
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(min_gas)),
+            format!("Please use at least {}Tgas", min_gas)
+        );
+
+        let validators: Vec<AccountId> = self
+            .validators
+            .iter()
+            .map(|validator| validator.account_id.clone())
+            .collect();
+
+        let (entries, queries) = self.query_accounts(&validators, Gas::from_tgas(10));
+
+        queries.then(
+            Promise::new(env::current_account_id()).function_call(
+                "recover_lock_callback".to_string(),
+                json!({ "lock_id": lock_id, "validators": validators, "entries": entries })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(20),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn recover_lock_callback(
+        &mut self,
+        lock_id: u64,
+        validators: Vec<AccountId>,
+        entries: Vec<u64>,
+    ) -> bool {
+        let accounts = self.read_accounts(&entries);
+
+        for (validator, external_user) in validators.iter().zip(accounts) {
+            let Some(external_user) = external_user else {
+                log!("Failed to query the external pool {}", validator);
+                return false;
+            };
+
+            if !self.matches_external_state(validator, &external_user) {
+                // An operation changed the validator but its callback never
+                // arrived, the state needs to be reconciled before unlocking
+                log!("External state of {} does not match ours", validator);
+                return false;
+            }
+        }
+
+        if let Some(lock) = self.release_lock(lock_id) {
+            self.log_lock("lock_recovered", &lock);
+        }
+        true
+    }
+
+    // Takes the lock of an operation with the external pools, operations
+    // that do not conflict with it can keep running. Returns the lock id
+    pub(crate) fn start_interacting(&mut self, operation: Operation) -> u64 {
+        let (expired, active): (Vec<InteractionLock>, Vec<InteractionLock>) = self
+            .pool
+            .locks
+            .drain(..)
+            .partition(|lock| lock.is_expired());
+        self.pool.locks = active;
+
+        for lock in expired {
+            self.log_lock("lock_expired", &lock);
+            self.reset_operation(&lock.operation);
+        }
+
+        require!(
+            !self
+                .pool
+                .locks
+                .iter()
+                .any(|lock| lock.operation.conflicts_with(&operation)),
+            "Already interacting with the staking contract"
+        );
+
+        let id = self.pool.next_lock_id;
+        self.pool.next_lock_id += 1;
+        self.pool.locks.push(InteractionLock {
+            id,
+            owner: env::predecessor_account_id(),
+            since: env::block_timestamp_ms(),
+            operation,
+        });
+
+        id
+    }
+
+    // Callbacks of an expired lock must not release the lock of a newer operation
+    pub(crate) fn stop_interacting(&mut self, lock_id: u64) {
+        if self.release_lock(lock_id).is_none() {
+            log!("The lock {} was already released", lock_id);
+        }
+    }
+
+    // Whether the operation that took the lock still owns it
+    pub(crate) fn holds_lock(&self, lock_id: u64) -> bool {
+        self.pool.locks.iter().any(|lock| lock.id == lock_id)
+    }
+
+    pub(crate) fn is_interacting(&self) -> bool {
+        !self.pool.locks.is_empty()
+    }
+
+    pub(crate) fn is_running(&self, operation: &Operation) -> bool {
+        self.pool
+            .locks
+            .iter()
+            .any(|lock| &lock.operation == operation)
+    }
+
+    fn release_lock(&mut self, lock_id: u64) -> Option<InteractionLock> {
+        let idx = self.pool.locks.iter().position(|lock| lock.id == lock_id)?;
+
+        let lock = self.pool.locks.remove(idx);
+        self.reset_operation(&lock.operation);
+        Some(lock)
+    }
+
+    // Forgets the callbacks a turn was waiting for
+    fn reset_operation(&mut self, operation: &Operation) {
+        if matches!(operation, Operation::Unstake | Operation::Withdraw) {
+            self.pool.turn_calls.clear();
+        }
+    }
+
+    fn matches_external_state(&self, validator: &AccountId, external_user: &ExternalUser) -> bool {
+        let Some(idx) = self.find_validator(validator) else {
+            return true;
+        };
+        let local = &self.validators[idx];
+
+        // Rewards only grow the staked balance, unstaked funds stay still
+        let unstaked_diff = match external_user.unstaked_balance > local.unstaking {
+            true => external_user
+                .unstaked_balance
+                .saturating_sub(local.unstaking),
+            false => local
+                .unstaking
+                .saturating_sub(external_user.unstaked_balance),
+        };
+
+        unstaked_diff <= ROUNDING_TOLERANCE
+            && external_user
+                .staked_balance
+                .saturating_add(ROUNDING_TOLERANCE)
+                >= local.staked
+    }
+
+    fn log_lock(&self, event: &str, lock: &InteractionLock) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": lock,
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_lock_expires() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        let expired = contract.start_interacting(Operation::Unstake);
+        let lock = contract.get_locks()[0].clone();
+        assert_eq!(lock.owner, "contract".parse::<AccountId>().unwrap());
+        assert_eq!(lock.operation, Operation::Unstake);
+
+        // Once expired, the next operation takes over
+        set_context(LOCK_TIMEOUT);
+        let id = contract.start_interacting(Operation::Unstake);
+        let locks = contract.get_locks();
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].id, id);
+        assert_eq!(locks[0].since, LOCK_TIMEOUT);
+
+        // A late callback of the expired operation keeps the new lock
+        contract.stop_interacting(expired);
+        assert!(contract.holds_lock(id));
+
+        contract.stop_interacting(id);
+        assert!(!contract.is_interacting());
+    }
+
+    #[test]
+    #[should_panic(expected = "Already interacting with the staking contract")]
+    fn test_lock_blocks_until_timeout() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        contract.start_interacting(Operation::UpdatePrize);
+        set_context(LOCK_TIMEOUT - 1);
+        contract.start_interacting(Operation::Unstake);
+    }
+
+    #[test]
+    fn test_concurrent_operations() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        // Withdrawing does not touch the staked balance the prize is read from
+        let withdraw = contract.start_interacting(Operation::Withdraw);
+        let prize = contract.start_interacting(Operation::UpdatePrize);
+        assert!(contract.is_running(&Operation::Withdraw));
+        assert!(contract.is_running(&Operation::UpdatePrize));
+
+        contract.stop_interacting(withdraw);
+        assert!(contract.is_interacting());
+
+        contract.stop_interacting(prize);
+        assert!(!contract.is_interacting());
+
+        // A migration can run next to a withdraw turn, but not next to an unstake
+        for (operation, conflicts) in [
+            (Operation::UpdatePrize, true),
+            (Operation::Unstake, true),
+            (Operation::Withdraw, false),
+            (Operation::Migration, true),
+        ] {
+            assert_eq!(Operation::Migration.conflicts_with(&operation), conflicts);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Already interacting with the staking contract")]
+    fn test_prize_blocked_while_unstaking() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        contract.start_interacting(Operation::Unstake);
+        contract.start_interacting(Operation::UpdatePrize);
+    }
+
+    #[test]
+    fn test_matches_external_state() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        let external_user = |staked: u128, unstaked: u128| ExternalUser {
+            account_id: "contract".parse().unwrap(),
+            unstaked_balance: NearToken::from_near(unstaked),
+            staked_balance: NearToken::from_near(staked),
+            can_withdraw: false,
+        };
+
+        contract.validators[0].staked = NearToken::from_near(5);
+
+        // Rewards are fine, funds that moved without us knowing are not
+        assert!(contract.matches_external_state(&accounts(0), &external_user(6, 0)));
+        assert!(!contract.matches_external_state(&accounts(0), &external_user(3, 2)));
+    }
+
+    fn set_context(timestamp_ms: u64) {
+        testing_env!(context(&"contract".parse().unwrap())
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
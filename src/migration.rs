@@ -0,0 +1,385 @@
+use crate::journal::CallKind;
+use crate::lock::Operation;
+use crate::pool::ExternalUser;
+use crate::*;
+use near_sdk::{near, require, serde_json::json, Gas, Promise, PromiseError, PromiseOrValue};
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationPhase {
+    // Unstake everything we have in the old validator
+    Unstake,
+    // Wait for the epochs to pass and withdraw from the old validator
+    Withdraw,
+    // Stake the withdrawn funds in the new validator
+    Restake,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct Migration {
+    pub from: AccountId,
+    pub to: AccountId,
+    // Funds unstaked from `from` and not yet staked in `to`
+    pub in_transit: NearToken,
+    pub phase: MigrationPhase,
+    pub unlock_epoch: u64,
+}
+
+#[near]
+impl Contract {
+    pub fn get_migration(&self) -> Option<Migration> {
+        self.migration.clone()
+    }
+
+    // Timelocked, returns the id of the proposal
+    pub fn start_migration(&mut self, from: AccountId, to: AccountId) -> u64 {
+        self.propose_change(Change::Migration { from, to })
+    }
+
+    pub(crate) fn begin_migration(&mut self, from: AccountId, to: AccountId) {
+        require!(self.migration.is_none(), "Already migrating");
+        require!(from != to, "Cannot migrate to the same validator");
+
+        let from_idx = self.validator_index(&from);
+        require!(
+            self.validators[from_idx].unstaking.is_zero(),
+            "Withdraw the pending unstake from the validator first"
+        );
+        require!(
+            !self.validators[from_idx].staked.is_zero(),
+            "Nothing to migrate, change the weights instead"
+        );
+
+        // The new validator takes over the allocation of the old one
+        let weight = self.validators[from_idx].weight;
+        self.validators[from_idx].weight = 0;
+
+        match self.find_validator(&to) {
+            Some(to_idx) => self.validators[to_idx].weight += weight,
+            None => self.insert_validator(to.clone(), weight),
+        }
+
+        self.migration = Some(Migration {
+            from,
+            to,
+            in_transit: NearToken::from_yoctonear(0),
+            phase: MigrationPhase::Unstake,
+            unlock_epoch: 0,
+        });
+
+        self.log_migration("migration_started");
+    }
+
+    // Anyone can move the migration forward, like `interact_external`
+    pub fn continue_migration(&mut self) -> Promise {
+        self.require_not_paused(PauseFlag::External);
+        require!(env::prepaid_gas() >= Gas::from_tgas(200), "Not enough gas");
+
+        let migration = self.migration.clone().expect("No migration in progress");
+
+        // Funds only leave the old validator once the new one is confirmed
+        let to_idx = self.validator_index(&migration.to);
+        require!(
+            self.validators[to_idx].verified,
+            "The new validator is not verified yet"
+        );
+
+        // Block interaction with external pool, funds are moving
+        let lock_id = self.start_interacting(Operation::Migration);
+
+        let (validator, call) = match migration.phase {
+            // Our count misses the rewards, ask the validator what we really have
+            MigrationPhase::Unstake => {
+                let (entries, query) = self.query_accounts(&[migration.from], Gas::from_tgas(10));
+
+                return query.then(
+                    Promise::new(env::current_account_id()).function_call(
+                        "continue_migration_unstake".to_string(),
+                        json!({ "lock_id": lock_id, "entry_id": entries[0] })
+                            .to_string()
+                            .into_bytes(),
+                        NO_DEPOSIT,
+                        Gas::from_tgas(170),
+                    ),
+                );
+            }
+            MigrationPhase::Withdraw => {
+                require!(
+                    env::epoch_height() >= migration.unlock_epoch,
+                    "Not enough time has passed"
+                );
+                let call = Promise::new(migration.from.clone()).function_call(
+                    "withdraw_all".to_string(),
+                    NO_ARGS,
+                    NO_DEPOSIT,
+                    Gas::from_tgas(120),
+                );
+                (migration.from, call)
+            }
+            MigrationPhase::Restake => {
+                let call = Promise::new(migration.to.clone()).function_call(
+                    "deposit_and_stake".to_string(),
+                    NO_ARGS,
+                    migration.in_transit,
+                    Gas::from_tgas(120),
+                );
+                (migration.to, call)
+            }
+        };
+        let amount = migration.in_transit;
+        let entry_id = self.record_call(CallKind::Migration, &validator, amount);
+
+        call.then(self.migration_callback(amount, entry_id, lock_id))
+    }
+
+    #[private]
+    pub fn continue_migration_unstake(
+        &mut self,
+        lock_id: u64,
+        entry_id: u64,
+        #[callback_result] account: Result<ExternalUser, PromiseError>,
+    ) -> PromiseOrValue<bool> {
+        self.complete_call(entry_id, account.is_ok());
+
+        // The lock expired, another call might be moving the funds already
+        if !self.holds_lock(lock_id) {
+            log!("The migration lock expired, nothing was unstaked");
+            return PromiseOrValue::Value(false);
+        }
+
+        let Ok(account) = account else {
+            log!("Failed to query the external pool, the unstake is retried");
+            self.stop_interacting(lock_id);
+            return PromiseOrValue::Value(false);
+        };
+
+        let from = self
+            .migration
+            .clone()
+            .expect("No migration in progress")
+            .from;
+        let amount = account.staked_balance;
+        let entry_id = self.record_call(CallKind::Migration, &from, amount);
+
+        let call = Promise::new(from)
+            .function_call(
+                "unstake".to_string(),
+                json!({ "amount": amount }).to_string().into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(120),
+            )
+            .then(self.migration_callback(amount, entry_id, lock_id));
+
+        PromiseOrValue::Promise(call)
+    }
+
+    #[private]
+    pub fn continue_migration_callback(
+        &mut self,
+        amount: NearToken,
+        entry_id: u64,
+        lock_id: u64,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
+
+        self.stop_interacting(lock_id);
+
+        let mut migration = self.migration.clone().expect("No migration in progress");
+
+        if call_result.is_err() {
+            // Nothing moved, the same phase is retried next time
+            log!("Error while migrating funds in phase {:?}", migration.phase);
+            return false;
+        }
+
+        match migration.phase {
+            MigrationPhase::Unstake => {
+                // Everything left, rewards included
+                let idx = self.validator_index(&migration.from);
+                self.validators[idx].staked = NearToken::from_yoctonear(0);
+
+                migration.in_transit = migration.in_transit.saturating_add(amount);
+                migration.unlock_epoch = env::epoch_height() + self.config.epochs_wait;
+                migration.phase = MigrationPhase::Withdraw;
+                self.migration = Some(migration);
+                self.log_migration("migration_unstaked");
+            }
+            MigrationPhase::Withdraw => {
+                // The old validator holds nothing anymore
+                let idx = self.validator_index(&migration.from);
+                self.validators.remove(idx);
+
+                migration.phase = MigrationPhase::Restake;
+                self.migration = Some(migration);
+                self.log_migration("migration_withdrawn");
+            }
+            MigrationPhase::Restake => {
+                let idx = self.validator_index(&migration.to);
+                self.validators[idx].staked = self.validators[idx].staked.saturating_add(amount);
+
+                migration.in_transit = NearToken::from_yoctonear(0);
+                self.migration = Some(migration);
+                self.log_migration("migration_completed");
+                self.migration = None;
+            }
+        }
+
+        true
+    }
+
+    fn migration_callback(&self, amount: NearToken, entry_id: u64, lock_id: u64) -> Promise {
+        Promise::new(env::current_account_id()).function_call(
+            "continue_migration_callback".to_string(),
+            json!({ "amount": amount, "entry_id": entry_id, "lock_id": lock_id })
+                .to_string()
+                .into_bytes(),
+            NO_DEPOSIT,
+            Gas::from_tgas(30),
+        )
+    }
+
+    // Funds that left the old validator but are not staked in the new one yet
+    pub(crate) fn migrating_amount(&self) -> NearToken {
+        self.migration
+            .as_ref()
+            .map(|migration| migration.in_transit)
+            .unwrap_or(NearToken::from_yoctonear(0))
+    }
+
+    pub(crate) fn is_migrating_from(&self, validator: &AccountId) -> bool {
+        self.migration
+            .as_ref()
+            .is_some_and(|migration| &migration.from == validator)
+    }
+
+    fn log_migration(&self, event: &str) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": &self.migration,
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_migration_flow() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+        contract.config.epochs_wait = 2;
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.validators[0].staked = near(10);
+
+        contract.begin_migration(accounts(0), accounts(1));
+        assert_eq!(contract.validators[0].weight, 0);
+        assert_eq!(contract.validators[1].weight, 1);
+
+        // Deposits go to the new validator while migrating
+        assert_eq!(contract.validator_for_deposit(near(1)), accounts(1));
+
+        // The rewards the validator earned move along with the tickets
+        contract.continue_migration();
+        set_context(0);
+        contract.continue_migration_unstake(0, 0, Ok(external_user(12)));
+        contract.continue_migration_callback(near(12), 1, 0, Ok(()));
+
+        let migration = contract.get_migration().unwrap();
+        assert_eq!(migration.phase, MigrationPhase::Withdraw);
+        assert_eq!(migration.in_transit, near(12));
+        assert_eq!(migration.unlock_epoch, 2);
+        assert_eq!(contract.validators[0].staked, near(0));
+        assert_eq!(contract.migrating_amount(), near(12));
+
+        // A failed withdraw is retried
+        set_context(2);
+        contract.continue_migration();
+        contract.continue_migration_callback(near(12), 2, 1, Err(PromiseError::Failed));
+        assert_eq!(
+            contract.get_migration().unwrap().phase,
+            MigrationPhase::Withdraw
+        );
+
+        // Once the funds are back the old validator is dropped
+        set_context(2);
+        contract.continue_migration();
+        contract.continue_migration_callback(near(12), 3, 2, Ok(()));
+        assert_eq!(
+            contract.get_migration().unwrap().phase,
+            MigrationPhase::Restake
+        );
+        assert_eq!(contract.validators.len(), 1);
+        assert_eq!(contract.validators[0].account_id, accounts(1));
+
+        set_context(2);
+        contract.continue_migration();
+        contract.continue_migration_callback(near(12), 4, 3, Ok(()));
+        assert!(contract.get_migration().is_none());
+        assert_eq!(contract.validators[0].staked, near(12));
+        assert!(!contract.is_interacting());
+    }
+
+    #[test]
+    fn test_migration_query_fails() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+        contract.validators[0].staked = NearToken::from_near(1);
+
+        contract.begin_migration(accounts(0), accounts(1));
+        contract.continue_migration();
+        set_context(0);
+        contract.continue_migration_unstake(0, 0, Err(PromiseError::Failed));
+
+        // Nothing was unstaked, the next call asks again
+        assert!(!contract.is_interacting());
+        assert_eq!(
+            contract.get_migration().unwrap().phase,
+            MigrationPhase::Unstake
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough time has passed")]
+    fn test_migration_waits_epochs() {
+        set_context(0);
+        let mut contract = Contract::for_tests();
+
+        contract.validators[0].staked = NearToken::from_near(1);
+
+        contract.begin_migration(accounts(0), accounts(1));
+        contract.continue_migration();
+        set_context(0);
+        contract.continue_migration_unstake(0, 0, Ok(external_user(1)));
+        contract.continue_migration_callback(NearToken::from_near(1), 1, 0, Ok(()));
+        contract.continue_migration();
+    }
+
+    fn external_user(staked: u128) -> ExternalUser {
+        ExternalUser {
+            account_id: "contract".parse().unwrap(),
+            unstaked_balance: NearToken::from_near(0),
+            staked_balance: NearToken::from_near(staked),
+            can_withdraw: false,
+        }
+    }
+
+    fn set_context(epoch: u64) {
+        testing_env!(context(&"contract".parse().unwrap())
+            .epoch_height(epoch)
+            .build());
+    }
+}
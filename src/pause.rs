@@ -0,0 +1,201 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json};
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PauseFlag {
+    Deposit,
+    Unstake,
+    Withdraw,
+    Raffle,
+    PrizeUpdate,
+    // Calls to the validators, the sweeps and the migration
+    External,
+}
+
+impl PauseFlag {
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::Deposit,
+            Self::Unstake,
+            Self::Withdraw,
+            Self::Raffle,
+            Self::PrizeUpdate,
+            Self::External,
+        ]
+    }
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Paused {
+    pub deposit: bool,
+    pub unstake: bool,
+    pub withdraw: bool,
+    pub raffle: bool,
+    pub prize_update: bool,
+    pub external: bool,
+}
+
+impl Paused {
+    pub fn all() -> Self {
+        Self {
+            deposit: true,
+            unstake: true,
+            withdraw: true,
+            raffle: true,
+            prize_update: true,
+            external: true,
+        }
+    }
+
+    pub fn is_paused(&self, flag: &PauseFlag) -> bool {
+        match flag {
+            PauseFlag::Deposit => self.deposit,
+            PauseFlag::Unstake => self.unstake,
+            PauseFlag::Withdraw => self.withdraw,
+            PauseFlag::Raffle => self.raffle,
+            PauseFlag::PrizeUpdate => self.prize_update,
+            PauseFlag::External => self.external,
+        }
+    }
+
+    fn set(&mut self, flag: &PauseFlag, paused: bool) {
+        let switch = match flag {
+            PauseFlag::Deposit => &mut self.deposit,
+            PauseFlag::Unstake => &mut self.unstake,
+            PauseFlag::Withdraw => &mut self.withdraw,
+            PauseFlag::Raffle => &mut self.raffle,
+            PauseFlag::PrizeUpdate => &mut self.prize_update,
+            PauseFlag::External => &mut self.external,
+        };
+        *switch = paused;
+    }
+}
+
+#[near]
+impl Contract {
+    // The operator can also pause what it tunes
+    pub fn pause(&mut self, flags: Vec<PauseFlag>) {
+        for flag in flags.iter() {
+            match flag {
+                PauseFlag::Raffle | PauseFlag::PrizeUpdate | PauseFlag::External => {
+                    self.require_role(&[Role::Pauser, Role::Guardian, Role::Operator])
+                }
+                _ => self.require_role(&[Role::Pauser, Role::Guardian]),
+            }
+        }
+
+        let mut paused = self.config.paused.clone();
+        flags.iter().for_each(|flag| paused.set(flag, true));
+        self.set_paused("pause", paused);
+    }
+
+    // Users get their funds back without waiting for the guardian quorum.
+    // Deposits, unstakes and the external calls resume through `propose_action`
+    pub fn resume(&mut self, flags: Vec<PauseFlag>) {
+        for flag in flags.iter() {
+            match flag {
+                PauseFlag::Withdraw => self.require_role(&[Role::Guardian]),
+                PauseFlag::Raffle | PauseFlag::PrizeUpdate => {
+                    self.require_role(&[Role::Operator, Role::Guardian])
+                }
+                _ => env::panic_str(&format!(
+                    "Resuming {:?} needs the guardians' approval, use propose_action",
+                    flag
+                )),
+            }
+        }
+
+        let mut paused = self.config.paused.clone();
+        flags.iter().for_each(|flag| paused.set(flag, false));
+        self.set_paused("resume", paused);
+    }
+
+    pub(crate) fn set_paused(&mut self, method: &str, paused: Paused) {
+        let old = json!({ "paused": &self.config.paused });
+        self.config.paused = paused;
+        self.record_admin_action(method, old, json!({ "paused": &self.config.paused }));
+    }
+
+    pub(crate) fn resume_flags(&mut self, flags: &[PauseFlag]) -> (Paused, Paused) {
+        let old = self.config.paused.clone();
+        flags
+            .iter()
+            .for_each(|flag| self.config.paused.set(flag, false));
+        (old, self.config.paused.clone())
+    }
+
+    pub(crate) fn require_not_paused(&self, flag: PauseFlag) {
+        require!(
+            self.upgrade.is_none(),
+            "The state is being upgraded, we will be back soon"
+        );
+        require!(
+            !self.config.paused.is_paused(&flag),
+            format!("{:?} is paused, we will be back soon", flag)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::set_context;
+
+    use crate::guardians::CriticalAction;
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn test_pause_flags() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.grant_role(Role::Pauser, accounts(1));
+        contract.grant_role(Role::Operator, accounts(2));
+
+        set_context(&accounts(1));
+        contract.emergency_start();
+        assert_eq!(contract.get_config().paused, Paused::all());
+
+        // Users can leave while the incident lasts
+        set_context(&"guardian".parse().unwrap());
+        contract.resume(vec![PauseFlag::Withdraw]);
+        assert!(!contract.get_config().paused.withdraw);
+        assert!(contract.get_config().paused.deposit);
+
+        set_context(&accounts(2));
+        contract.resume(vec![PauseFlag::Raffle]);
+        contract.pause(vec![PauseFlag::External]);
+        assert!(!contract.get_config().paused.raffle);
+
+        set_context(&"guardian".parse().unwrap());
+        contract.propose_action(CriticalAction::Resume(vec![
+            PauseFlag::Deposit,
+            PauseFlag::External,
+        ]));
+        let paused = contract.get_config().paused;
+        assert!(!paused.deposit && !paused.external);
+        assert!(paused.unstake && paused.prize_update);
+    }
+
+    #[test]
+    #[should_panic(expected = "Resuming Deposit needs the guardians' approval, use propose_action")]
+    fn test_resume_deposits_needs_approvals() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        contract.pause(vec![PauseFlag::Deposit]);
+        contract.resume(vec![PauseFlag::Deposit]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires the Pauser or Guardian role")]
+    fn test_operator_cannot_pause_deposits() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.grant_role(Role::Operator, accounts(2));
+
+        set_context(&accounts(2));
+        contract.pause(vec![PauseFlag::Deposit]);
+    }
+}
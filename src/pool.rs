@@ -1,16 +1,25 @@
+use crate::health::HealthKind;
+use crate::journal::CallKind;
+use crate::lock::{InteractionLock, Operation};
 use crate::*;
-use near_sdk::{json_types::U128, near, require, serde_json::json, Gas, Promise, PromiseError};
+use near_sdk::{
+    json_types::U128, near, require, serde_json::json, Gas, GasWeight, Promise, PromiseError,
+    PromiseOrValue,
+};
 
 // Amount of time between prize updates (10 min)
 // To avoid blocking the interaction with external pool
 const PRIZE_UPDATE_INTERVAL: u64 = 600000;
 
+// Gas (in Tgas) of the callback journaling each ping
+const PING_CALLBACK_GAS: u64 = 5;
+
 #[near(serializers=[json])]
 pub struct ExternalUser {
-    account_id: AccountId,
-    unstaked_balance: NearToken,
-    staked_balance: NearToken,
-    can_withdraw: bool,
+    pub(crate) account_id: AccountId,
+    pub(crate) unstaked_balance: NearToken,
+    pub(crate) staked_balance: NearToken,
+    pub(crate) can_withdraw: bool,
 }
 
 #[near(serializers=[borsh, json])]
@@ -22,7 +31,20 @@ pub struct Pool {
     pub pool_fee: u8,
     pub next_raffle: u64,
     pub tickets: NearToken,
-    pub is_interacting: bool,
+    pub pending_deposits: NearToken,
+    pub queued_deposits: NearToken,
+    // Tickets being swapped through the liquid staking, waiting for the callback
+    pub instant_exits: NearToken,
+    pub locks: Vec<InteractionLock>,
+    pub next_lock_id: u64,
+    pub raffles_frozen: bool,
+    pub external_balance: NearToken,
+    // Sum of the users' unstaked balances, withdrawn or not
+    pub users_unstaked: NearToken,
+    // Journal entries of the current unstake or withdraw turn
+    pub turn_calls: Vec<u64>,
+    // Whether the last `interact_external` swept deposits instead of running a turn
+    pub swept_last: bool,
     pub next_withdraw_turn: u64,
     pub next_withdraw_epoch: u64,
     pub winners: Vec<(AccountId, NearToken)>,
@@ -32,6 +54,8 @@ pub struct Pool {
 pub struct PoolInfo {
     to_unstake: NearToken,
     tickets: NearToken,
+    pending_deposits: NearToken,
+    queued_deposits: NearToken,
     prize: NearToken,
     last_prize_update: u64,
     pool_fee: u8,
@@ -40,18 +64,30 @@ pub struct PoolInfo {
     next_withdraw_turn: u64,
     next_withdraw_epoch: u64,
     pool_reserve: NearToken,
+    raffles_frozen: bool,
+    external_balance: NearToken,
+    solvency_ratio: u32,
 }
 
 impl Pool {
     pub(crate) fn new(first_raffle: u64) -> Self {
         Self {
             tickets: NearToken::from_yoctonear(0),
+            pending_deposits: NearToken::from_yoctonear(0),
+            queued_deposits: NearToken::from_yoctonear(0),
+            instant_exits: NearToken::from_yoctonear(0),
             to_unstake: NearToken::from_yoctonear(0),
             prize: NearToken::from_yoctonear(0),
             last_prize_update: 0,
             pool_fee: 0,
             next_raffle: first_raffle,
-            is_interacting: false,
+            locks: vec![],
+            next_lock_id: 0,
+            raffles_frozen: false,
+            external_balance: NearToken::from_yoctonear(0),
+            users_unstaked: NearToken::from_yoctonear(0),
+            turn_calls: vec![],
+            swept_last: false,
             next_withdraw_turn: 1,
             next_withdraw_epoch: 0,
             winners: vec![],
@@ -68,25 +104,41 @@ impl Contract {
             pool_reserve: NearToken::from_yoctonear(pool_reserve),
             to_unstake: self.pool.to_unstake,
             tickets: self.pool.tickets,
+            pending_deposits: self.pool.pending_deposits,
+            queued_deposits: self.pool.queued_deposits,
             prize: self.pool.prize,
             last_prize_update: self.pool.last_prize_update,
             pool_fee: self.pool.pool_fee,
             next_raffle: self.pool.next_raffle,
-            is_interacting: self.pool.is_interacting,
+            is_interacting: self.is_interacting(),
             next_withdraw_turn: self.pool.next_withdraw_turn,
             next_withdraw_epoch: self.pool.next_withdraw_epoch,
+            raffles_frozen: self.pool.raffles_frozen,
+            external_balance: self.pool.external_balance,
+            solvency_ratio: self.solvency_ratio(),
         }
     }
 
     #[payable]
-    pub fn deposit_and_stake(&mut self) -> Promise {
-        require!(!self.config.emergency, "We will be back soon");
-
+    pub fn deposit_and_stake(&mut self) -> PromiseOrValue<bool> {
+        self.require_not_paused(PauseFlag::Deposit);
         require!(
-            env::prepaid_gas().ge(&Gas::from_tgas(220)),
-            "Use at least 220Tgas"
+            self.shortfall.is_none(),
+            "The pool is absorbing a loss, try again later"
+        );
+        require!(
+            !self.health.degraded,
+            "Deposits are paused, the validators are unhealthy"
         );
 
+        // Batched deposits are staked later by `interact_external` or a keeper
+        if !self.config.batch_deposits {
+            require!(
+                env::prepaid_gas().ge(&Gas::from_tgas(220)),
+                "Use at least 220Tgas"
+            );
+        }
+
         let tickets = env::attached_deposit();
 
         require!(
@@ -99,7 +151,7 @@ impl Contract {
 
         let user = env::predecessor_account_id();
 
-        if self.users.tree.len() == 0 {
+        if self.users.tree.is_empty() {
             require!(
                 user == self.config.guardian,
                 "Only the guardian can deposit first"
@@ -111,7 +163,9 @@ impl Contract {
         }
 
         require!(
-            self.get_staked_for(&user) + tickets.as_yoctonear()
+            self.get_staked_for(&user)
+                + self.get_user(&user).pending_deposit
+                + tickets.as_yoctonear()
                 <= self.config.max_deposit.as_yoctonear(),
             format!(
                 "Surpassed the limit of {} tickets that a user can have",
@@ -119,14 +173,34 @@ impl Contract {
             )
         );
 
-        // Deposit the tokens in the external pool
-        // Add the tickets to the pool, but not yet to the user (rollback if failed)
-        self.pool.tickets = self.pool.tickets.saturating_add(tickets);
+        // Track the tickets as pending until the external pool confirms them
+        self.add_pending_deposit_for(&user, tickets);
+
+        if self.config.batch_deposits {
+            self.queue_deposit_for(&user, tickets);
+
+            let event_args = json!({
+                "standard": "nep297",
+                "version": "1.0.0",
+                "event": "queue_deposit",
+                "data": {
+                    "user": &user,
+                    "amount": &tickets,
+                },
+            });
+
+            log!("EVENT_JSON:{}", event_args.to_string());
+            return PromiseOrValue::Value(true);
+        }
+
+        // Deposit the tokens in the validator furthest below its target
+        let validator = self.validator_for_deposit(tickets);
+        let entry_id = self.record_call(CallKind::Deposit, &validator, tickets);
 
         // Todo: check validity - We add 100yn to cover the cost of staking in an external pool
         let deposit = env::attached_deposit().saturating_add(NearToken::from_yoctonear(1));
 
-        Promise::new(self.config.external_pool.clone())
+        Promise::new(validator.clone())
             .function_call(
                 "deposit_and_stake".to_string(),
                 NO_ARGS,
@@ -136,13 +210,19 @@ impl Contract {
             .then(
                 Promise::new(env::current_account_id()).function_call(
                     "deposit_and_stake_callback".to_string(),
-                    json!({"user": user, "tickets_amount": tickets})
-                        .to_string()
-                        .into_bytes(),
+                    json!({
+                        "user": user,
+                        "tickets_amount": tickets,
+                        "validator": validator,
+                        "entry_id": entry_id,
+                    })
+                    .to_string()
+                    .into_bytes(),
                     NO_DEPOSIT,
                     Gas::from_tgas(50),
                 ),
             )
+            .into()
     }
 
     #[private]
@@ -151,16 +231,27 @@ impl Contract {
         #[callback_result] call_result: Result<(), PromiseError>,
         user: AccountId,
         tickets_amount: NearToken,
+        validator: AccountId,
+        entry_id: u64,
     ) -> bool {
-        // It failed, remove tickets from the pool and return the tokens to the user
-        if call_result.is_err() {
-            self.pool.tickets = self.pool.tickets.saturating_sub(tickets_amount);
+        if !self.complete_call(entry_id, call_result.is_ok()) {
+            return false;
+        }
 
+        // The deposit is no longer in flight, whatever the result
+        self.remove_pending_deposit_for(&user, tickets_amount);
+
+        // It failed, return the tokens to the user
+        if call_result.is_err() {
             log!("Failed attempt to deposit in the pool, returning tokens to the user");
-            Promise::new(user.clone()).transfer(tickets_amount);
+            self.transfer_to(&user, tickets_amount);
             false
         } else {
-            // It worked, give tickets to the user
+            // It worked, give tickets to the user and the pool
+            let idx = self.validator_index(&validator);
+            self.validators[idx].staked =
+                self.validators[idx].staked.saturating_add(tickets_amount);
+            self.pool.tickets = self.pool.tickets.saturating_add(tickets_amount);
             self.stake_tickets_for(&user, tickets_amount.as_yoctonear());
 
             let event_args = json!({
@@ -182,8 +273,16 @@ impl Contract {
     pub fn unstake(&mut self, amount: NearToken) {
         let user = env::predecessor_account_id();
 
-        require!(!self.config.emergency, "We will be back soon");
+        self.require_not_paused(PauseFlag::Unstake);
+        require!(
+            self.shortfall.is_none(),
+            "The pool is absorbing a loss, try again later"
+        );
         require!(self.is_registered(&user), "User not registered in the pool");
+        require!(
+            user != self.config.guardian,
+            "Reserve withdrawals need the guardians' approval"
+        );
 
         let user_tickets = self.get_staked_for(&user);
 
@@ -192,6 +291,13 @@ impl Contract {
             format!("Amount cant exceed {}", user_tickets)
         );
 
+        self.unstake_for(&user, amount);
+    }
+
+    // Moves `amount` of the user's tickets to the next unstake turn
+    pub(crate) fn unstake_for(&mut self, user: &AccountId, amount: NearToken) {
+        let user_tickets = self.get_staked_for(user);
+
         let mut unstake_amount = amount;
 
         let withdraw_all: bool =
@@ -204,10 +310,10 @@ impl Contract {
         self.pool.to_unstake = self.pool.to_unstake.saturating_add(amount);
 
         // the user will be able to withdraw in the next withdraw_turn
-        self.set_withdraw_turn_for(&user, self.pool.next_withdraw_turn);
+        self.set_withdraw_turn_for(user, self.pool.next_withdraw_turn);
 
         // update user info
-        self.unstake_tickets_for(&user, amount);
+        self.unstake_tickets_for(user, amount);
 
         let event_args = json!({
             "standard": "nep297",
@@ -227,28 +333,24 @@ impl Contract {
     pub fn withdraw_all(&mut self) {
         let user = env::predecessor_account_id();
 
-        require!(!self.config.emergency, "We will be back soon");
+        self.require_not_paused(PauseFlag::Withdraw);
         require!(
-            env::prepaid_gas().ge(&Gas::from_tgas(20)),
-            "Use at least 20Tgas"
+            env::prepaid_gas().ge(&Gas::from_tgas(30)),
+            "Use at least 30Tgas"
         );
         require!(self.is_registered(&user), "User is not registered");
 
-        require!(
-            self.pool.next_withdraw_turn >= self.get_withdraw_turn_for(&user).unwrap(),
-            "Withdraw not ready"
-        );
+        let unstaked = self.get_user(&user).unstaked;
+        require!(unstaked != 0, "Nothing to withdraw");
 
-        let amount = self.withdraw_all_for(&user);
-        require!(amount != 0, "Nothing to withdraw");
+        // Paid once the validators returned the funds of the user's turn
+        let (available, _) = self.withdrawal_wait(unstaked, self.get_withdraw_turn_for(&user));
+        require!(available, "Withdraw not ready");
 
-        require!(
-            env::epoch_height() >= self.pool.next_withdraw_epoch,
-            "Not enough time has passed"
-        );
+        let amount = self.withdraw_all_for(&user);
 
         // Transfer the tokens to the user
-        Promise::new(user.clone()).transfer(NearToken::from_yoctonear(amount));
+        self.transfer_to(&user, NearToken::from_yoctonear(amount));
 
         let event_args = json!({
             "standard": "nep297",
@@ -265,7 +367,12 @@ impl Contract {
 
     // Raffle ---------------------------------------------------------------------
     pub fn raffle(&mut self) -> AccountId {
-        require!(!self.config.emergency, "We will be back soon");
+        self.require_not_paused(PauseFlag::Raffle);
+        require!(!self.pool.raffles_frozen, "Raffles are frozen");
+        require!(
+            !self.health.degraded,
+            "Raffles are paused, the validators are unhealthy"
+        );
         require!(!self.users.tree.len() > 3, "No users in the pool");
 
         let now: u64 = env::block_timestamp_ms();
@@ -291,6 +398,7 @@ impl Contract {
 
         // add the prize to the pool, and reset the prize_pool
         self.pool.tickets = self.pool.tickets.saturating_add(prize);
+        self.credit_validators(prize);
 
         // Set next raffle time
         self.pool.next_raffle = now + self.config.time_between_raffles;
@@ -304,11 +412,17 @@ impl Contract {
     }
 
     pub fn update_prize(&mut self) -> Promise {
-        require!(!self.config.emergency, "We will be back soon");
+        self.require_not_paused(PauseFlag::PrizeUpdate);
+        // The liquid staking already burnt the shares of in-flight exits
+        require!(
+            self.pool.instant_exits.is_zero(),
+            "Instant exits in flight, try again later"
+        );
 
+        let min_gas = self.prize_update_gas();
         require!(
-            env::prepaid_gas().gt(&Gas::from_tgas(40)),
-            "Please use at least 40Tgas"
+            env::prepaid_gas().ge(&Gas::from_tgas(min_gas)),
+            format!("Please use at least {}Tgas", min_gas)
         );
 
         let now: u64 = env::block_timestamp_ms();
@@ -320,47 +434,147 @@ impl Contract {
         );
 
         // Block interaction with external pool
-        self.start_interacting();
-
-        Promise::new(self.config.external_pool.clone())
-            .function_call(
-                "get_account".to_string(),
-                json!({ "account_id": env::current_account_id()})
-                    .to_string()
-                    .into_bytes(),
-                NO_DEPOSIT,
-                Gas::from_tgas(40), // Todo: Check the Gas amount
+        let lock_id = self.start_interacting(Operation::UpdatePrize);
+
+        // Ask every validator for our balance, the callback receives one result each
+        let args = json!({ "account_id": env::current_account_id()})
+            .to_string()
+            .into_bytes();
+        let gas = self.config.prize_gas.clone();
+        let validators: Vec<AccountId> = self
+            .validators
+            .iter()
+            .map(|validator| validator.account_id.clone())
+            .collect();
+
+        let (entries, calls): (Vec<u64>, Vec<Promise>) = validators
+            .iter()
+            .map(|validator| {
+                let entry_id = self.record_call(CallKind::Query, validator, NO_DEPOSIT);
+                let get_account = Promise::new(validator.clone()).function_call_weight(
+                    "get_account".to_string(),
+                    args.clone(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(gas.get_account),
+                    GasWeight(1),
+                );
+
+                if !self.config.ping_before_prize {
+                    return (entry_id, get_account);
+                }
+
+                // `get_account` runs even if the validator has no `ping`, so
+                // the callback only ever sees the result of `get_account`
+                let ping_id = self.record_call(CallKind::Ping, validator, NO_DEPOSIT);
+                let call = Promise::new(validator.clone())
+                    .function_call(
+                        "ping".to_string(),
+                        NO_ARGS,
+                        NO_DEPOSIT,
+                        Gas::from_tgas(gas.ping),
+                    )
+                    .then(Promise::new(env::current_account_id()).function_call(
+                        "ping_callback".to_string(),
+                        json!({ "entry_id": ping_id }).to_string().into_bytes(),
+                        NO_DEPOSIT,
+                        Gas::from_tgas(PING_CALLBACK_GAS),
+                    ))
+                    .then(get_account);
+
+                (entry_id, call)
+            })
+            .unzip();
+
+        calls
+            .into_iter()
+            .reduce(|joint, call| joint.and(call))
+            .expect("No validator to query")
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "update_prize_callback".to_string(),
+                    json!({ "lock_id": lock_id, "entries": entries })
+                        .to_string()
+                        .into_bytes(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(gas.callback),
+                ),
             )
-            .then(Promise::new(env::current_account_id()).function_call(
-                "update_prize_callback".to_string(),
-                NO_ARGS,
-                NO_DEPOSIT,
-                Gas::from_tgas(40), // Todo: Check the Gas amount
-            ))
     }
 
     #[private]
-    pub fn update_prize_callback(
+    pub fn ping_callback(
         &mut self,
-        #[callback_result] call_result: Result<ExternalUser, PromiseError>,
-    ) -> NearToken {
+        entry_id: u64,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) {
+        self.complete_call(entry_id, call_result.is_ok());
+    }
+
+    // Gas `update_prize` needs for the current validators, in Tgas
+    pub(crate) fn prize_update_gas(&self) -> u64 {
+        let gas = &self.config.prize_gas;
+        let ping = match self.config.ping_before_prize {
+            true => gas.ping + PING_CALLBACK_GAS,
+            false => 0,
+        };
+
+        gas.callback + (ping + gas.get_account + 5) * self.validators.len() as u64
+    }
+
+    #[private]
+    pub fn update_prize_callback(&mut self, lock_id: u64, entries: Vec<u64>) -> NearToken {
+        let accounts = self.read_accounts(&entries);
+
+        // The lock expired, the balances might have moved since we asked
+        if !self.holds_lock(lock_id) {
+            log!("The prize update arrived too late");
+            return self.pool.prize;
+        }
+
         // Unblock interaction with external pool
-        self.stop_interacting();
+        self.stop_interacting(lock_id);
 
         let mut prize: NearToken = self.pool.prize;
 
-        if call_result.is_err() {
-            // Todo: emit event?
-            log!("Failed to update the prize");
-            return prize;
+        // Funds moving between validators are still ours
+        let mut staked_in_external = self.migrating_amount();
+        // What each validator holds, in the order we queried them
+        let mut balances = Vec::new();
+
+        for external_user in accounts {
+            match external_user {
+                Some(external_user) => {
+                    staked_in_external =
+                        staked_in_external.saturating_add(external_user.staked_balance);
+                    balances.push(external_user.staked_balance);
+                }
+                None => {
+                    log!("Failed to update the prize");
+                    self.record_health(HealthKind::PrizeUpdate, false);
+                    return prize;
+                }
+            }
         }
+        self.record_health(HealthKind::PrizeUpdate, true);
 
-        let staked_in_external: NearToken = call_result.unwrap().staked_balance;
+        // The validators hold less than our tickets, there is no prize but a loss
+        if self.check_shortfall(staked_in_external, &balances) {
+            self.pool.prize = NearToken::from_yoctonear(0);
+            self.pool.last_prize_update = env::block_timestamp_ms();
+            return self.pool.prize;
+        }
 
         // The difference between the staked_balance in the external pool and the
-        // tickets we have in our pool is the prize
-        if staked_in_external.gt(&self.pool.tickets) {
-            prize = staked_in_external.saturating_sub(self.pool.tickets);
+        // tickets we have in our pool is the prize. In-flight deposits might already
+        // be staked in the external pool, so they are never counted as prize
+        let in_flight = self
+            .pool
+            .pending_deposits
+            .saturating_sub(self.pool.queued_deposits);
+        let accounted = self.pool.tickets.saturating_add(in_flight);
+
+        if staked_in_external.gt(&accounted) {
+            prize = staked_in_external.saturating_sub(accounted);
         }
 
         // Update prize_pool
@@ -380,8 +594,11 @@ impl Contract {
         self.pool.winners.len()
     }
 
-    #[private]
-    pub fn set_pool_fee(&mut self, fee: u8) {
-        self.pool.pool_fee = fee;
+    // Timelocked, returns the id of the proposal
+    pub fn set_pool_fee(&mut self, fee: u8) -> u64 {
+        self.propose_change(Change::Config(ConfigPatch {
+            pool_fee: Some(fee),
+            ..Default::default()
+        }))
     }
 }
@@ -0,0 +1,386 @@
+use crate::shortfall::mul_div;
+use crate::*;
+use near_sdk::{near, require, serde_json, serde_json::json, Gas, GasWeight, Promise};
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct ValidatorBalance {
+    pub account_id: AccountId,
+    // What we think the validator holds
+    pub staked: NearToken,
+    pub unstaking: NearToken,
+    // What the validator reports
+    pub external_staked: NearToken,
+    pub external_unstaked: NearToken,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discrepancy {
+    pub check: String,
+    pub expected: NearToken,
+    pub actual: NearToken,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct Reconciliation {
+    pub timestamp: u64,
+    pub validators: Vec<ValidatorBalance>,
+    pub tickets: NearToken,
+    pub to_unstake: NearToken,
+    pub prize: NearToken,
+    pub users_staked: NearToken,
+    pub users_unstaked: NearToken,
+    pub discrepancies: Vec<Discrepancy>,
+    // Whether the guardian corrected the drift afterwards
+    pub fixed: bool,
+}
+
+#[near]
+impl Contract {
+    // Last report, operators check it before and after every incident
+    pub fn get_reconciliation(&self) -> Option<Reconciliation> {
+        self.reconciliation.clone()
+    }
+
+    // Compares our accounting with the validators. The report is stored, so
+    // it needs an operator or guardian, and only the guardian can fix the drift
+    pub fn reconcile(&mut self, fix: bool) -> Promise {
+        if fix {
+            self.require_role(&[Role::Guardian]);
+            self.record_admin_action("reconcile", serde_json::Value::Null, json!({ "fix": true }));
+        } else {
+            self.require_role(&[Role::Operator, Role::Guardian]);
+        }
+
+        let min_gas = 60 + 15 * self.validators.len() as u64;
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(min_gas)),
+            format!("Please use at least {}Tgas", min_gas)
+        );
+
+        let validators: Vec<AccountId> = self
+            .validators
+            .iter()
+            .map(|validator| validator.account_id.clone())
+            .collect();
+
+        let (entries, queries) = self.query_accounts(&validators, Gas::from_tgas(10));
+
+        // The callback compares every balance, it gets most of the gas
+        queries.then(
+            Promise::new(env::current_account_id()).function_call_weight(
+                "reconcile_callback".to_string(),
+                json!({ "validators": validators, "fix": fix, "entries": entries })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(30),
+                GasWeight(1),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn reconcile_callback(
+        &mut self,
+        validators: Vec<AccountId>,
+        fix: bool,
+        entries: Vec<u64>,
+    ) -> Option<Reconciliation> {
+        let accounts = self.read_accounts(&entries);
+        let mut balances = Vec::new();
+
+        for (account_id, external_user) in validators.into_iter().zip(accounts) {
+            let Some(external_user) = external_user else {
+                log!("Failed to query the external pool {}", account_id);
+                return None;
+            };
+
+            let (staked, unstaking) = match self.find_validator(&account_id) {
+                Some(idx) => (self.validators[idx].staked, self.validators[idx].unstaking),
+                None => (NearToken::from_yoctonear(0), NearToken::from_yoctonear(0)),
+            };
+
+            balances.push(ValidatorBalance {
+                account_id,
+                staked,
+                unstaking,
+                external_staked: external_user.staked_balance,
+                external_unstaked: external_user.unstaked_balance,
+            });
+        }
+
+        let mut reconciliation = self.reconciliation_for(balances);
+
+        if fix && !reconciliation.discrepancies.is_empty() {
+            reconciliation.fixed = self.fix_accounting(&reconciliation);
+        }
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "reconciliation",
+            "data": &reconciliation,
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+
+        self.reconciliation = Some(reconciliation.clone());
+        Some(reconciliation)
+    }
+
+    // Compares our accounting with the balances the validators report
+    fn reconciliation_for(&self, validators: Vec<ValidatorBalance>) -> Reconciliation {
+        let zero = NearToken::from_yoctonear(0);
+        let mut discrepancies = Vec::new();
+        let mut check = |name: &str, expected: NearToken, actual: NearToken, ok: bool| {
+            if !ok {
+                discrepancies.push(Discrepancy {
+                    check: name.to_string(),
+                    expected,
+                    actual,
+                });
+            }
+        };
+
+        let users_staked =
+            NearToken::from_yoctonear(self.users.tree.get(0).map_or(0, |root| root.weight));
+        // Running sum kept by the users, walking all of them would not fit in a call
+        let users_unstaked = self.pool.users_unstaked;
+
+        // Tickets leave the users when they unstake, and the pool once the validators
+        // unstake or the liquid staking swaps them
+        let expected_tickets = users_staked
+            .saturating_add(self.pool.to_unstake)
+            .saturating_add(self.pool.instant_exits);
+        check(
+            "tickets",
+            expected_tickets,
+            self.pool.tickets,
+            expected_tickets == self.pool.tickets,
+        );
+
+        // Funds moving between validators are still tickets
+        let staked = validators.iter().fold(self.migrating_amount(), |total, v| {
+            total.saturating_add(v.staked)
+        });
+        check(
+            "validators_staked",
+            self.pool.tickets,
+            staked,
+            staked == self.pool.tickets,
+        );
+
+        let mut external_staked = self.migrating_amount();
+        let mut unstaking = zero;
+        for validator in validators.iter() {
+            external_staked = external_staked.saturating_add(validator.external_staked);
+            unstaking = unstaking.saturating_add(validator.unstaking);
+
+            // Rewards only grow the staked balance, unstaked funds stay still
+            check(
+                &format!("external_staked:{}", validator.account_id),
+                validator.staked,
+                validator.external_staked,
+                validator.external_staked.saturating_add(ROUNDING_TOLERANCE) >= validator.staked,
+            );
+
+            let unstaked_diff = match validator.external_unstaked > validator.unstaking {
+                true => validator
+                    .external_unstaked
+                    .saturating_sub(validator.unstaking),
+                false => validator
+                    .unstaking
+                    .saturating_sub(validator.external_unstaked),
+            };
+            check(
+                &format!("external_unstaked:{}", validator.account_id),
+                validator.unstaking,
+                validator.external_unstaked,
+                unstaked_diff <= ROUNDING_TOLERANCE,
+            );
+        }
+
+        // The validators hold the tickets, the prize and the deposits in flight
+        let in_flight = self
+            .pool
+            .pending_deposits
+            .saturating_sub(self.pool.queued_deposits);
+        let expected_staked = self
+            .pool
+            .tickets
+            .saturating_add(self.pool.prize)
+            .saturating_add(in_flight);
+        check(
+            "external_staked",
+            expected_staked,
+            external_staked,
+            external_staked.saturating_add(ROUNDING_TOLERANCE) >= expected_staked,
+        );
+
+        // Unstaked funds are waiting for a turn, in the validators or already withdrawn
+        let waiting = self.pool.to_unstake.saturating_add(unstaking);
+        check(
+            "users_unstaked",
+            waiting,
+            users_unstaked,
+            users_unstaked >= waiting,
+        );
+
+        let storage_cost = env::storage_byte_cost().saturating_mul(env::storage_usage() as u128);
+        let liquid = env::account_balance().saturating_sub(storage_cost);
+        let expected_liquid = users_unstaked
+            .saturating_sub(waiting)
+            .saturating_add(self.refunds.total)
+            .saturating_add(self.pool.queued_deposits);
+        check(
+            "liquid_balance",
+            expected_liquid,
+            liquid,
+            liquid >= expected_liquid,
+        );
+
+        Reconciliation {
+            timestamp: env::block_timestamp_ms(),
+            validators,
+            tickets: self.pool.tickets,
+            to_unstake: self.pool.to_unstake,
+            prize: self.pool.prize,
+            users_staked,
+            users_unstaked,
+            discrepancies,
+            fixed: false,
+        }
+    }
+
+    // The users' tickets are the source of truth, the pool and the
+    // validators are brought back in line with them. Nothing changes while
+    // funds are moving, the report was taken before they arrive
+    fn fix_accounting(&mut self, reconciliation: &Reconciliation) -> bool {
+        if self.is_interacting() || self.migration.is_some() || !self.pool.instant_exits.is_zero() {
+            log!("Cannot fix the accounting while funds are moving");
+            return false;
+        }
+
+        let tickets = reconciliation
+            .users_staked
+            .saturating_add(reconciliation.to_unstake);
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "fix_accounting",
+            "data": {
+                "old_tickets": self.pool.tickets,
+                "new_tickets": tickets,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+
+        self.pool.tickets = tickets;
+
+        // Split the tickets following what each validator really holds
+        let external: Vec<u128> = reconciliation
+            .validators
+            .iter()
+            .map(|v| match self.find_validator(&v.account_id) {
+                Some(_) => v.external_staked.as_yoctonear(),
+                None => 0,
+            })
+            .collect();
+        let total: u128 = external.iter().sum();
+        if total == 0 {
+            return true;
+        }
+
+        let mut remaining = tickets.as_yoctonear();
+        for (validator, held) in reconciliation.validators.iter().zip(external) {
+            let Some(idx) = self.find_validator(&validator.account_id) else {
+                continue;
+            };
+
+            let share = mul_div(tickets.as_yoctonear(), held, total).min(remaining);
+            remaining -= share;
+            self.validators[idx].staked = NearToken::from_yoctonear(share);
+            self.validators[idx].unstaking = validator.external_unstaked;
+        }
+
+        // Rounding leftovers go to the first validator holding funds
+        if let Some(validator) = self.validators.iter_mut().find(|v| !v.staked.is_zero()) {
+            validator.staked = validator
+                .staked
+                .saturating_add(NearToken::from_yoctonear(remaining));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::set_context;
+
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn test_reconciliation() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.add_new_user(&accounts(2));
+        contract.stake_tickets_for(&accounts(2), near(10).as_yoctonear());
+        contract.validators[0].staked = near(5);
+        contract.validators[1].staked = near(5);
+        contract.pool.tickets = near(10);
+
+        let balance = |idx: usize, staked: u128, external_staked: u128| ValidatorBalance {
+            account_id: accounts(idx),
+            staked: near(staked),
+            unstaking: near(0),
+            external_staked: near(external_staked),
+            external_unstaked: near(0),
+        };
+
+        // Everything matches, rewards are not a discrepancy
+        let reconciliation = contract.reconciliation_for(vec![balance(0, 5, 6), balance(1, 5, 5)]);
+        assert!(reconciliation.discrepancies.is_empty());
+        assert_eq!(reconciliation.users_staked, near(10));
+
+        // Tickets drifted from the users' stake
+        contract.pool.tickets = near(12);
+        let reconciliation = contract.reconciliation_for(vec![balance(0, 5, 6), balance(1, 5, 6)]);
+        assert_eq!(
+            reconciliation.discrepancies[0],
+            Discrepancy {
+                check: "tickets".to_string(),
+                expected: near(10),
+                actual: near(12),
+            }
+        );
+        assert_eq!(reconciliation.discrepancies[1].check, "validators_staked");
+
+        // Fixing follows the users' stake and what the validators hold
+        assert!(contract.fix_accounting(&reconciliation));
+        assert_eq!(contract.pool.tickets, near(10));
+        assert_eq!(contract.validators[0].staked, near(5));
+        assert_eq!(contract.validators[1].staked, near(5));
+
+        let reconciliation = contract.reconciliation_for(vec![balance(0, 5, 6), balance(1, 5, 6)]);
+        assert!(reconciliation.discrepancies.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires the Operator or Guardian role")]
+    fn test_reconcile_needs_role() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        set_context(&accounts(2));
+        contract.reconcile(false);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json, store::IterableMap, Gas, Promise, PromiseError};
+
+#[near(serializers=[borsh])]
+pub struct Refunds {
+    pub total: NearToken,
+    pub ledger: IterableMap<AccountId, NearToken>,
+}
+
+impl Default for Refunds {
+    fn default() -> Self {
+        Self {
+            total: NearToken::from_yoctonear(0),
+            ledger: IterableMap::new(StorageKey::Refunds),
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    // Every outbound transfer goes through here, so failed ones end up in the ledger
+    pub(crate) fn transfer_to(&self, user: &AccountId, amount: NearToken) -> Promise {
+        Promise::new(user.clone()).transfer(amount).then(
+            Promise::new(env::current_account_id()).function_call(
+                "transfer_callback".to_string(),
+                json!({ "user": user, "amount": amount })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(10),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn transfer_callback(
+        &mut self,
+        user: AccountId,
+        amount: NearToken,
+        #[callback_result] call_result: Result<(), PromiseError>,
+    ) -> bool {
+        if call_result.is_ok() {
+            return true;
+        }
+
+        // The tokens came back to us, keep them claimable by the user
+        log!("Failed to transfer to {}, saving it as a refund", &user);
+        self.add_refund_for(&user, amount);
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "refund_pending",
+            "data": {
+                "user": &user,
+                "amount": &amount,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        false
+    }
+
+    pub fn claim_refund(&mut self) -> Promise {
+        let user = env::predecessor_account_id();
+
+        self.require_not_paused(PauseFlag::Withdraw);
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(30)),
+            "Use at least 30Tgas"
+        );
+
+        let amount = self
+            .refunds
+            .ledger
+            .remove(&user)
+            .expect("No refund to claim");
+        self.refunds.total = self.refunds.total.saturating_sub(amount);
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "claim_refund",
+            "data": {
+                "user": &user,
+                "amount": &amount,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+
+        self.transfer_to(&user, amount)
+    }
+
+    pub fn get_refund_for(&self, user: AccountId) -> NearToken {
+        self.refunds
+            .ledger
+            .get(&user)
+            .copied()
+            .unwrap_or(NearToken::from_yoctonear(0))
+    }
+
+    pub fn get_refunds(&self, from: usize, until: usize) -> Vec<(AccountId, NearToken)> {
+        self.refunds
+            .ledger
+            .iter()
+            .skip(from)
+            .take(until.saturating_sub(from))
+            .map(|(user, amount)| (user.clone(), *amount))
+            .collect()
+    }
+
+    pub fn get_number_of_refunds(&self) -> u32 {
+        self.refunds.ledger.len()
+    }
+
+    pub(crate) fn add_refund_for(&mut self, user: &AccountId, amount: NearToken) {
+        let pending = self.get_refund_for(user.clone());
+        self.refunds
+            .ledger
+            .insert(user.clone(), pending.saturating_add(amount));
+        self.refunds.total = self.refunds.total.saturating_add(amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::set_context;
+
+    #[test]
+    fn test_refund_ledger() {
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        // Successful transfers leave nothing behind
+        assert!(contract.transfer_callback(user.clone(), NearToken::from_near(1), Ok(())));
+        assert_eq!(contract.get_number_of_refunds(), 0);
+
+        // Failed transfers accumulate in the ledger
+        for _ in 0..2 {
+            assert!(!contract.transfer_callback(
+                user.clone(),
+                NearToken::from_near(1),
+                Err(PromiseError::Failed)
+            ));
+        }
+        assert_eq!(
+            contract.get_refund_for(user.clone()),
+            NearToken::from_near(2)
+        );
+        assert_eq!(
+            contract.get_refunds(0, 10),
+            vec![(user.clone(), NearToken::from_near(2))]
+        );
+        assert_eq!(contract.refunds.total, NearToken::from_near(2));
+
+        set_context(&user);
+        contract.claim_refund();
+        assert_eq!(contract.get_refund_for(user), NearToken::from_near(0));
+        assert_eq!(contract.refunds.total, NearToken::from_near(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "No refund to claim")]
+    fn test_claim_without_refund() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        set_context(&"user".parse().unwrap());
+        contract.claim_refund();
+    }
+}
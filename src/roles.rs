@@ -0,0 +1,288 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json, store::IterableMap};
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Role {
+    // Grants the other roles, holds all of them but Guardian implicitly
+    Owner,
+    // Recovers the pool from incidents and lifts a pause
+    Guardian,
+    // Pauses the pool, cannot resume it
+    Pauser,
+    // Tunes the raffles, the external calls and the validators
+    Operator,
+    FeeManager,
+}
+
+#[near(serializers=[borsh])]
+pub struct Roles {
+    pub owner: AccountId,
+    // Ownership moves only once the new owner accepts it
+    pub pending_owner: Option<AccountId>,
+    pub members: IterableMap<AccountId, Vec<Role>>,
+}
+
+#[near(serializers=[json])]
+pub struct RolesInfo {
+    pub owner: AccountId,
+    pub pending_owner: Option<AccountId>,
+    pub members: Vec<(AccountId, Vec<Role>)>,
+}
+
+impl Roles {
+    pub fn new(owner: AccountId) -> Self {
+        Self {
+            owner,
+            pending_owner: None,
+            members: IterableMap::new(StorageKey::Roles),
+        }
+    }
+
+    // The owner holds every role but the guardian's, guardians keep their
+    // powers apart from whoever administers the pool
+    pub fn has_role(&self, account_id: &AccountId, role: &Role) -> bool {
+        (account_id == &self.owner && role != &Role::Guardian)
+            || self
+                .members
+                .get(account_id)
+                .is_some_and(|roles| roles.contains(role))
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_roles(&self) -> RolesInfo {
+        RolesInfo {
+            owner: self.roles.owner.clone(),
+            pending_owner: self.roles.pending_owner.clone(),
+            members: self
+                .roles
+                .members
+                .iter()
+                .map(|(account_id, roles)| (account_id.clone(), roles.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        self.roles.has_role(&account_id, &role)
+    }
+
+    pub fn grant_role(&mut self, role: Role, account_id: AccountId) {
+        self.require_role(&[Role::Owner]);
+        require!(role != Role::Owner, "Use transfer_ownership instead");
+        require!(
+            role != Role::Guardian,
+            "Guardians change through propose_change"
+        );
+        require!(
+            !self.roles_of(&account_id).contains(&role),
+            "Role already granted"
+        );
+
+        let old = json!({ "account_id": &account_id, "roles": self.roles_of(&account_id) });
+        self.grant_role_to(role, &account_id);
+        let new = json!({ "account_id": &account_id, "roles": self.roles_of(&account_id) });
+        self.record_admin_action("grant_role", old, new);
+    }
+
+    pub fn revoke_role(&mut self, role: Role, account_id: AccountId) {
+        self.require_role(&[Role::Owner]);
+        require!(
+            role != Role::Guardian,
+            "Guardians change through propose_change"
+        );
+        require!(
+            self.roles_of(&account_id).contains(&role),
+            "Role not granted"
+        );
+
+        let old = json!({ "account_id": &account_id, "roles": self.roles_of(&account_id) });
+        self.revoke_role_from(&role, &account_id);
+        let new = json!({ "account_id": &account_id, "roles": self.roles_of(&account_id) });
+        self.record_admin_action("revoke_role", old, new);
+    }
+
+    // First step of the transfer, `None` cancels a pending one
+    pub fn transfer_ownership(&mut self, new_owner: Option<AccountId>) {
+        self.require_role(&[Role::Owner]);
+        require!(
+            new_owner.as_ref() != Some(&self.roles.owner),
+            "Already the owner"
+        );
+
+        let old = json!({ "pending_owner": &self.roles.pending_owner });
+        self.roles.pending_owner = new_owner.clone();
+        self.record_admin_action(
+            "transfer_ownership",
+            old,
+            json!({ "pending_owner": &new_owner }),
+        );
+        self.log_ownership("ownership_transfer_started", new_owner);
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let caller = env::predecessor_account_id();
+        require!(
+            self.roles.pending_owner.as_ref() == Some(&caller),
+            "Not the pending owner"
+        );
+
+        let old = json!({ "owner": &self.roles.owner });
+        self.roles.owner = caller.clone();
+        self.roles.pending_owner = None;
+        self.record_admin_action("accept_ownership", old, json!({ "owner": &caller }));
+        self.log_ownership("ownership_transferred", Some(caller));
+    }
+
+    // Does nothing if the account already holds the role
+    pub(crate) fn grant_role_to(&mut self, role: Role, account_id: &AccountId) {
+        let mut roles = self.roles_of(account_id);
+        if roles.contains(&role) {
+            return;
+        }
+
+        roles.push(role.clone());
+        self.roles.members.insert(account_id.clone(), roles);
+        self.log_role("role_granted", &role, account_id);
+    }
+
+    // Does nothing if the account does not hold the role
+    pub(crate) fn revoke_role_from(&mut self, role: &Role, account_id: &AccountId) {
+        let mut roles = self.roles_of(account_id);
+        if !roles.contains(role) {
+            return;
+        }
+
+        roles.retain(|granted| granted != role);
+        match roles.is_empty() {
+            true => self.roles.members.remove(account_id),
+            false => self.roles.members.insert(account_id.clone(), roles),
+        };
+        self.log_role("role_revoked", role, account_id);
+    }
+
+    fn roles_of(&self, account_id: &AccountId) -> Vec<Role> {
+        self.roles
+            .members
+            .get(account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Admin methods call this first, the owner passes every check
+    pub(crate) fn require_role(&self, roles: &[Role]) {
+        let caller = env::predecessor_account_id();
+
+        if !roles.iter().any(|role| self.roles.has_role(&caller, role)) {
+            let names: Vec<String> = roles.iter().map(|role| format!("{:?}", role)).collect();
+            env::panic_str(&format!("Requires the {} role", names.join(" or ")));
+        }
+    }
+
+    fn log_role(&self, event: &str, role: &Role, account_id: &AccountId) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": {
+                "role": role,
+                "account_id": account_id,
+                "by": env::predecessor_account_id(),
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+
+    fn log_ownership(&self, event: &str, new_owner: Option<AccountId>) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": {
+                "owner": &self.roles.owner,
+                "new_owner": new_owner,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::set_context;
+
+    use near_sdk::test_utils::accounts;
+
+    #[test]
+    fn test_roles() {
+        // The contract account owns the pool, whoever deploys it
+        set_context(&accounts(3));
+        let mut contract = Contract::for_tests();
+        assert_eq!(
+            contract.get_roles().owner,
+            "contract".parse::<AccountId>().unwrap()
+        );
+
+        set_context(&"contract".parse().unwrap());
+
+        // The reserve account starts as guardian
+        assert!(contract.has_role("guardian".parse().unwrap(), Role::Guardian));
+        assert!(!contract.has_role("guardian".parse().unwrap(), Role::Pauser));
+
+        // The owner holds the other roles, but cannot act as a guardian
+        assert!(contract.has_role("contract".parse().unwrap(), Role::Operator));
+        assert!(!contract.has_role("contract".parse().unwrap(), Role::Guardian));
+
+        contract.grant_role(Role::Pauser, accounts(1));
+        contract.grant_role(Role::FeeManager, accounts(1));
+
+        set_context(&accounts(1));
+        contract.emergency_start();
+        let id = contract.set_pool_fee(5);
+        assert_eq!(contract.get_proposal(id).unwrap().proposer, accounts(1));
+
+        set_context(&"contract".parse().unwrap());
+        contract.revoke_role(Role::FeeManager, accounts(1));
+        assert_eq!(
+            contract.get_roles().members,
+            vec![
+                ("guardian".parse().unwrap(), vec![Role::Guardian]),
+                (accounts(1), vec![Role::Pauser])
+            ]
+        );
+
+        // Ownership moves only once accepted
+        contract.transfer_ownership(Some(accounts(2)));
+        assert!(!contract.has_role(accounts(2), Role::Operator));
+
+        set_context(&accounts(2));
+        contract.accept_ownership();
+        assert!(contract.has_role(accounts(2), Role::Operator));
+        assert!(!contract.has_role("contract".parse().unwrap(), Role::Operator));
+        assert!(contract.get_roles().pending_owner.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Guardians change through propose_change")]
+    fn test_guardian_role_timelocked() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        contract.grant_role(Role::Guardian, accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires the FeeManager role")]
+    fn test_role_required() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+
+        set_context(&"guardian".parse().unwrap());
+        contract.set_pool_fee(5);
+    }
+}
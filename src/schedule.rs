@@ -0,0 +1,466 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json, store::LookupMap};
+
+// Maximum number of pending scheduled unstakes a single user can have
+const MAX_SCHEDULES_PER_USER: usize = 5;
+
+// Maximum number of scheduled unstakes executed per `interact_external`,
+// so the call never runs out of gas when many schedules trigger together
+const MAX_SCHEDULES_PER_CALL: usize = 20;
+
+// Timestamp triggers are indexed by the hour they fall in (ms)
+const TIME_BUCKET: u64 = 3_600_000;
+
+// Buckets walked per call, empty ones included, after a long idle period
+// the next calls catch up
+const MAX_BUCKETS_PER_CALL: u64 = 48;
+
+// Pays the storage of the schedule, given back when it triggers or is cancelled
+const SCHEDULE_DEPOSIT: NearToken = NearToken::from_millinear(10);
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnstakeTrigger {
+    // Unstake once the block timestamp (in ms) reaches the given time
+    Timestamp(U64),
+    // Unstake once the given number of raffles has taken place
+    Raffle(u64),
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct ScheduledUnstake {
+    pub id: u64,
+    pub user: AccountId,
+    pub amount: NearToken,
+    pub trigger: UnstakeTrigger,
+}
+
+#[near(serializers=[borsh])]
+pub struct Schedules {
+    pub next_id: u64,
+    pub pending: LookupMap<u64, ScheduledUnstake>,
+    pub by_user: LookupMap<AccountId, Vec<u64>>,
+    // Ids by hour of their timestamp and by raffle number
+    pub by_time: LookupMap<u64, Vec<u64>>,
+    pub by_raffle: LookupMap<u64, Vec<u64>>,
+    // First buckets that might still hold schedules
+    pub next_time_bucket: u64,
+    pub next_raffle: u64,
+    // Storage deposits of the pending schedules
+    pub deposits: NearToken,
+}
+
+impl Default for Schedules {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            pending: LookupMap::new(StorageKey::Schedules),
+            by_user: LookupMap::new(StorageKey::SchedulesByUser),
+            by_time: LookupMap::new(StorageKey::SchedulesByTime),
+            by_raffle: LookupMap::new(StorageKey::SchedulesByRaffle),
+            next_time_bucket: env::block_timestamp_ms() / TIME_BUCKET,
+            next_raffle: 0,
+            deposits: NearToken::from_yoctonear(0),
+        }
+    }
+}
+
+impl Schedules {
+    // Index of the trigger and its bucket
+    fn bucket_of(&mut self, trigger: &UnstakeTrigger) -> (&mut LookupMap<u64, Vec<u64>>, u64) {
+        match trigger {
+            UnstakeTrigger::Timestamp(time) => (&mut self.by_time, time.0 / TIME_BUCKET),
+            UnstakeTrigger::Raffle(raffle) => (&mut self.by_raffle, *raffle),
+        }
+    }
+
+    fn insert(&mut self, scheduled: ScheduledUnstake) {
+        let (index, bucket) = self.bucket_of(&scheduled.trigger);
+        index.entry(bucket).or_default().push(scheduled.id);
+
+        self.by_user
+            .entry(scheduled.user.clone())
+            .or_default()
+            .push(scheduled.id);
+        self.pending.insert(scheduled.id, scheduled);
+    }
+
+    // The bucket is left untouched, callers walking it drop the id themselves
+    fn remove(&mut self, id: u64) -> Option<ScheduledUnstake> {
+        let scheduled = self.pending.remove(&id)?;
+
+        let ids = self
+            .by_user
+            .get_mut(&scheduled.user)
+            .expect("Missing index");
+        ids.retain(|other| *other != id);
+        if ids.is_empty() {
+            self.by_user.remove(&scheduled.user);
+        }
+
+        Some(scheduled)
+    }
+
+    fn remove_from_bucket(&mut self, scheduled: &ScheduledUnstake) {
+        let (index, bucket) = self.bucket_of(&scheduled.trigger);
+
+        if let Some(ids) = index.get_mut(&bucket) {
+            ids.retain(|other| *other != scheduled.id);
+            if ids.is_empty() {
+                index.remove(&bucket);
+            }
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    #[payable]
+    pub fn schedule_unstake(&mut self, amount: NearToken, trigger: UnstakeTrigger) -> u64 {
+        let user = env::predecessor_account_id();
+
+        self.require_not_paused(PauseFlag::Unstake);
+        require!(
+            env::attached_deposit() == SCHEDULE_DEPOSIT,
+            format!("Attach {} to cover the storage", SCHEDULE_DEPOSIT)
+        );
+        require!(self.is_registered(&user), "User not registered in the pool");
+        require!(
+            user != self.config.guardian,
+            "Reserve withdrawals need the guardians' approval"
+        );
+        require!(!amount.is_zero(), "Amount must be positive");
+
+        let user_tickets = self.get_staked_for(&user);
+        require!(
+            amount.as_yoctonear() <= user_tickets,
+            format!("Amount cant exceed {}", user_tickets)
+        );
+
+        require!(
+            !self.is_triggered(&trigger),
+            "Trigger is already in the past"
+        );
+
+        let scheduled_count = self.schedules.by_user.get(&user).map_or(0, |ids| ids.len());
+        require!(
+            scheduled_count < MAX_SCHEDULES_PER_USER,
+            format!(
+                "Cannot have more than {} scheduled unstakes",
+                MAX_SCHEDULES_PER_USER
+            )
+        );
+
+        let id = self.schedules.next_id;
+        self.schedules.next_id += 1;
+
+        let scheduled = ScheduledUnstake {
+            id,
+            user: user.clone(),
+            amount,
+            trigger,
+        };
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "schedule_unstake",
+            "data": &scheduled,
+        });
+
+        self.schedules.insert(scheduled);
+        self.schedules.deposits = self.schedules.deposits.saturating_add(SCHEDULE_DEPOSIT);
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+
+        id
+    }
+
+    // The storage deposit goes back to the user
+    pub fn cancel_scheduled_unstake(&mut self, id: u64) {
+        let user = env::predecessor_account_id();
+
+        let scheduled = self
+            .schedules
+            .pending
+            .get(&id)
+            .expect("Scheduled unstake not found");
+
+        require!(scheduled.user == user, "Not your scheduled unstake");
+
+        let scheduled = self.schedules.remove(id).unwrap();
+        self.schedules.remove_from_bucket(&scheduled);
+        self.schedules.deposits = self.schedules.deposits.saturating_sub(SCHEDULE_DEPOSIT);
+        self.transfer_to(&user, SCHEDULE_DEPOSIT);
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "cancel_scheduled_unstake",
+            "data": {
+                "user": user,
+                "id": id,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+
+    pub fn get_scheduled_unstakes(&self, user: AccountId) -> Vec<ScheduledUnstake> {
+        self.schedules
+            .by_user
+            .get(&user)
+            .map(|ids| {
+                ids.iter()
+                    .filter_map(|id| self.schedules.pending.get(id).cloned())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Moves the triggered schedules into `pool.to_unstake`, called from `interact_external`.
+    // Only the buckets that can hold triggered schedules are read
+    pub(crate) fn process_scheduled_unstakes(&mut self) {
+        // Users cannot leave while a loss is being socialized, and the
+        // schedules wait in their buckets while unstaking is paused
+        if self.shortfall.is_some() || self.config.paused.is_paused(&PauseFlag::Unstake) {
+            return;
+        }
+
+        let mut budget = MAX_SCHEDULES_PER_CALL;
+
+        // Every schedule of a past raffle number triggered
+        let raffles = self.pool.winners.len() as u64;
+        let mut walked = 0;
+        while self.schedules.next_raffle <= raffles && walked < MAX_BUCKETS_PER_CALL {
+            let bucket = self.schedules.next_raffle;
+            if !self.process_bucket(UnstakeTrigger::Raffle(bucket), &mut budget) {
+                break;
+            }
+            self.schedules.next_raffle += 1;
+            walked += 1;
+        }
+
+        // Past hours triggered entirely, the current one only in part
+        let now_bucket = env::block_timestamp_ms() / TIME_BUCKET;
+        walked = 0;
+        while self.schedules.next_time_bucket < now_bucket && walked < MAX_BUCKETS_PER_CALL {
+            let bucket = self.schedules.next_time_bucket;
+            let trigger = UnstakeTrigger::Timestamp(U64(bucket * TIME_BUCKET));
+            if !self.process_bucket(trigger, &mut budget) {
+                return;
+            }
+            self.schedules.next_time_bucket += 1;
+            walked += 1;
+        }
+
+        if self.schedules.next_time_bucket == now_bucket {
+            let trigger = UnstakeTrigger::Timestamp(U64(now_bucket * TIME_BUCKET));
+            self.process_bucket(trigger, &mut budget);
+        }
+    }
+
+    // Executes the triggered schedules of the bucket `trigger` falls in,
+    // returns whether the bucket is now empty
+    fn process_bucket(&mut self, trigger: UnstakeTrigger, budget: &mut usize) -> bool {
+        let (index, bucket) = self.schedules.bucket_of(&trigger);
+        let ids = index.remove(&bucket).unwrap_or_default();
+
+        let mut left = vec![];
+        for id in ids {
+            let scheduled = self.schedules.pending.get(&id).cloned();
+            match scheduled {
+                Some(scheduled) if *budget > 0 && self.is_triggered(&scheduled.trigger) => {
+                    *budget -= 1;
+                    self.execute_schedule(scheduled);
+                }
+                Some(_) => left.push(id),
+                None => {}
+            }
+        }
+
+        if left.is_empty() {
+            return true;
+        }
+
+        let (index, bucket) = self.schedules.bucket_of(&trigger);
+        index.insert(bucket, left);
+        false
+    }
+
+    fn execute_schedule(&mut self, scheduled: ScheduledUnstake) {
+        self.schedules.remove(scheduled.id);
+
+        // The user might have unstaked manually in the meantime
+        let user_tickets = self.get_staked_for(&scheduled.user);
+        let amount = scheduled.amount.as_yoctonear().min(user_tickets);
+
+        if amount > 0 {
+            self.unstake_for(&scheduled.user, NearToken::from_yoctonear(amount));
+        }
+
+        // Claimable through `claim_refund`, no transfer per schedule here
+        self.schedules.deposits = self.schedules.deposits.saturating_sub(SCHEDULE_DEPOSIT);
+        self.add_refund_for(&scheduled.user, SCHEDULE_DEPOSIT);
+    }
+
+    fn is_triggered(&self, trigger: &UnstakeTrigger) -> bool {
+        match trigger {
+            UnstakeTrigger::Timestamp(time) => env::block_timestamp_ms() >= time.0,
+            UnstakeTrigger::Raffle(raffle) => self.pool.winners.len() as u64 >= *raffle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_scheduled_unstake() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&guardian, NearToken::from_yoctonear(0), 0);
+        let mut contract = Contract::for_tests();
+        contract.config.min_deposit = NearToken::from_yoctonear(1);
+
+        contract.add_new_user(&guardian);
+        contract.stake_tickets_for(&guardian, 1);
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, SCHEDULE_DEPOSIT, 0);
+        let by_time = contract.schedule_unstake(
+            NearToken::from_yoctonear(4),
+            UnstakeTrigger::Timestamp(U64(1000)),
+        );
+        let by_raffle =
+            contract.schedule_unstake(NearToken::from_yoctonear(3), UnstakeTrigger::Raffle(1));
+        let later = contract.schedule_unstake(
+            NearToken::from_yoctonear(2),
+            UnstakeTrigger::Timestamp(U64(3 * TIME_BUCKET)),
+        );
+        assert_eq!(contract.get_scheduled_unstakes(user.clone()).len(), 3);
+        assert_eq!(
+            contract.schedules.deposits,
+            SCHEDULE_DEPOSIT.saturating_mul(3)
+        );
+
+        // Nothing triggered yet, tickets stay eligible
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 10);
+        assert_eq!(contract.pool.to_unstake, NearToken::from_yoctonear(0));
+
+        // Time trigger passes
+        set_context(&user, NearToken::from_yoctonear(0), 1000);
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 6);
+        assert_eq!(contract.pool.to_unstake, NearToken::from_yoctonear(4));
+        assert_eq!(contract.get_refund_for(user.clone()), SCHEDULE_DEPOSIT);
+
+        // Cancelled schedules never trigger
+        contract.cancel_scheduled_unstake(by_raffle);
+        contract
+            .pool
+            .winners
+            .push((user.clone(), NearToken::from_yoctonear(0)));
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 6);
+        assert!(contract.schedules.pending.get(&by_time).is_none());
+        assert_eq!(contract.schedules.next_raffle, 2);
+
+        // Hours without schedules are skipped on the way
+        set_context(&user, NearToken::from_yoctonear(0), 3 * TIME_BUCKET);
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 4);
+        assert!(contract.schedules.pending.get(&later).is_none());
+        assert!(contract.get_scheduled_unstakes(user.clone()).is_empty());
+        assert_eq!(contract.schedules.next_time_bucket, 3);
+        assert!(contract.schedules.deposits.is_zero());
+    }
+
+    #[test]
+    fn test_scheduled_unstake_paused() {
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(
+            &"guardian".parse().unwrap(),
+            NearToken::from_yoctonear(0),
+            0,
+        );
+        let mut contract = Contract::for_tests();
+
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, SCHEDULE_DEPOSIT, 0);
+        let id = contract.schedule_unstake(
+            NearToken::from_yoctonear(4),
+            UnstakeTrigger::Timestamp(U64(1000)),
+        );
+
+        // Triggered while paused, the schedule stays queued
+        contract.config.paused.unstake = true;
+        set_context(&user, NearToken::from_yoctonear(0), 1000);
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 10);
+        assert!(contract.pool.to_unstake.is_zero());
+        assert!(contract.schedules.pending.get(&id).is_some());
+
+        contract.config.paused.unstake = false;
+        contract.process_scheduled_unstakes();
+        assert_eq!(contract.get_staked_for(&user), 6);
+        assert_eq!(contract.pool.to_unstake, NearToken::from_yoctonear(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach 0.010 NEAR to cover the storage")]
+    fn test_schedule_needs_deposit() {
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(
+            &"guardian".parse().unwrap(),
+            NearToken::from_yoctonear(0),
+            0,
+        );
+        let mut contract = Contract::for_tests();
+
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, NearToken::from_yoctonear(0), 0);
+        contract.schedule_unstake(NearToken::from_yoctonear(5), UnstakeTrigger::Raffle(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not your scheduled unstake")]
+    fn test_cancel_others_schedule() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+
+        set_context(&guardian, NearToken::from_yoctonear(0), 0);
+        let mut contract = Contract::for_tests();
+
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, SCHEDULE_DEPOSIT, 0);
+        let id = contract.schedule_unstake(NearToken::from_yoctonear(5), UnstakeTrigger::Raffle(1));
+
+        set_context(&guardian, NearToken::from_yoctonear(0), 0);
+        contract.cancel_scheduled_unstake(id);
+    }
+
+    fn set_context(account: &AccountId, attached_deposit: NearToken, timestamp_ms: u64) {
+        testing_env!(context(account)
+            .account_balance(NearToken::from_near(20))
+            .attached_deposit(attached_deposit)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json};
+
+// Maximum number of users charged per `socialize_loss` call
+const MAX_USERS_PER_SOCIALIZE: u32 = 50;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct Shortfall {
+    pub deficit: NearToken,
+    pub reserve_absorbed: NearToken,
+    // Part of the deficit that users pay pro-rata to their tickets
+    pub to_socialize: NearToken,
+    pub socialized: NearToken,
+    // Total user tickets when the loss was detected
+    pub snapshot_total: NearToken,
+    // Next user node to charge
+    pub cursor: u32,
+}
+
+#[near]
+impl Contract {
+    pub fn get_shortfall(&self) -> Option<Shortfall> {
+        self.shortfall.clone()
+    }
+
+    // Anyone can move the socialization forward until every user paid its share
+    pub fn socialize_loss(&mut self) {
+        let mut shortfall = self.shortfall.clone().expect("No loss to socialize");
+
+        let until = (shortfall.cursor + MAX_USERS_PER_SOCIALIZE).min(self.users.tree.len());
+
+        for uid in shortfall.cursor..until {
+            let node = &self.users.tree[uid];
+            let loss = mul_div(
+                shortfall.to_socialize.as_yoctonear(),
+                node.staked,
+                shortfall.snapshot_total.as_yoctonear().max(1),
+            );
+
+            if loss > 0 {
+                let user = node.account_id.clone();
+                self.remove_tickets_from(&user, loss);
+                self.pool.tickets = self
+                    .pool
+                    .tickets
+                    .saturating_sub(NearToken::from_yoctonear(loss));
+                shortfall.socialized = shortfall
+                    .socialized
+                    .saturating_add(NearToken::from_yoctonear(loss));
+            }
+        }
+
+        shortfall.cursor = until;
+
+        if until < self.users.tree.len() {
+            self.shortfall = Some(shortfall);
+            return;
+        }
+
+        self.shortfall = None;
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "shortfall_socialized",
+            "data": &shortfall,
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+
+    pub fn resume_raffles(&mut self) {
+        self.require_role(&[Role::Guardian]);
+        require!(
+            self.shortfall.is_none(),
+            "The loss is still being socialized"
+        );
+
+        let old = json!({ "raffles_frozen": self.pool.raffles_frozen });
+        self.pool.raffles_frozen = false;
+        self.record_admin_action("resume_raffles", old, json!({ "raffles_frozen": false }));
+    }
+
+    // Ratio between what the validators hold and our tickets, in basis points
+    pub(crate) fn solvency_ratio(&self) -> u32 {
+        if self.pool.tickets.is_zero() {
+            return 10_000;
+        }
+
+        (self.pool.external_balance.as_yoctonear() * 10_000 / self.pool.tickets.as_yoctonear())
+            as u32
+    }
+
+    // Called from `update_prize_callback` with what the validators hold,
+    // in total and each of them
+    pub(crate) fn check_shortfall(
+        &mut self,
+        staked_in_external: NearToken,
+        balances: &[NearToken],
+    ) -> bool {
+        self.pool.external_balance = staked_in_external;
+
+        let deficit = self.pool.tickets.saturating_sub(staked_in_external);
+        if deficit <= ROUNDING_TOLERANCE {
+            return false;
+        }
+
+        // The slashed validators hold less than we counted, the tickets
+        // removed below leave with them
+        for (validator, balance) in self.validators.iter_mut().zip(balances) {
+            validator.staked = validator.staked.min(*balance);
+        }
+
+        // The remaining deficit is the loss already being socialized
+        if self.shortfall.is_some() {
+            return true;
+        }
+
+        self.pool.raffles_frozen = true;
+
+        // The guardian reserve absorbs the loss first
+        let guardian = self.config.guardian.clone();
+        let reserve = match self.is_registered(&guardian) {
+            true => self.get_staked_for(&guardian),
+            false => 0,
+        };
+        let reserve_absorbed = reserve.min(deficit.as_yoctonear());
+
+        if reserve_absorbed > 0 {
+            self.remove_tickets_from(&guardian, reserve_absorbed);
+            self.pool.tickets = self
+                .pool
+                .tickets
+                .saturating_sub(NearToken::from_yoctonear(reserve_absorbed));
+        }
+
+        let shortfall = Shortfall {
+            deficit,
+            reserve_absorbed: NearToken::from_yoctonear(reserve_absorbed),
+            to_socialize: deficit.saturating_sub(NearToken::from_yoctonear(reserve_absorbed)),
+            socialized: NearToken::from_yoctonear(0),
+            snapshot_total: NearToken::from_yoctonear(
+                self.users.tree.get(0).map_or(0, |root| root.weight),
+            ),
+            cursor: 0,
+        };
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "shortfall",
+            "data": &shortfall,
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+
+        if !shortfall.to_socialize.is_zero() {
+            self.shortfall = Some(shortfall);
+        }
+
+        true
+    }
+}
+
+// Computes a * b / c without overflowing, as long as the result fits in a u128
+pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    const MASK: u128 = u64::MAX as u128;
+
+    // 256 bits product as (hi, lo)
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let cross = (lo_lo >> 64) + (hi_lo & MASK) + (lo_hi & MASK);
+    let lo = (cross << 64) | (lo_lo & MASK);
+    let hi = a_hi * b_hi + (hi_lo >> 64) + (lo_hi >> 64) + (cross >> 64);
+
+    // Long division, one bit at a time
+    let mut quotient: u128 = 0;
+    let mut remainder: u128 = 0;
+    for bit in (0..256).rev() {
+        let next = match bit >= 128 {
+            true => (hi >> (bit - 128)) & 1,
+            false => (lo >> bit) & 1,
+        };
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | next;
+
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            if bit < 128 {
+                quotient |= 1 << bit;
+            }
+        }
+    }
+
+    quotient
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_shortfall() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        set_context();
+        let mut contract = Contract::for_tests();
+
+        let near = |amount: u128| NearToken::from_near(amount).as_yoctonear();
+        contract.add_new_user(&guardian);
+        contract.stake_tickets_for(&guardian, near(2));
+        for (user, tickets) in [(accounts(1), near(6)), (accounts(2), near(12))] {
+            contract.add_new_user(&user);
+            contract.stake_tickets_for(&user, tickets);
+        }
+        contract.pool.tickets = NearToken::from_near(20);
+        contract.insert_validator(accounts(3), 1);
+        contract.validators[0].staked = NearToken::from_near(10);
+        contract.validators[1].staked = NearToken::from_near(10);
+
+        // Rounding differences are not a loss
+        let almost = NearToken::from_yoctonear(near(20) - 1000);
+        let balances = [
+            NearToken::from_yoctonear(near(10) - 1000),
+            NearToken::from_near(10),
+        ];
+        assert!(!contract.check_shortfall(almost, &balances));
+        assert!(!contract.pool.raffles_frozen);
+        assert_eq!(contract.validators[0].staked, NearToken::from_near(10));
+
+        // The reserve pays the first 2 NEAR, users pay the other 3 pro-rata
+        let balances = [NearToken::from_near(10), NearToken::from_near(5)];
+        assert!(contract.check_shortfall(NearToken::from_near(15), &balances));
+        assert!(contract.pool.raffles_frozen);
+        assert_eq!(contract.validators[0].staked, NearToken::from_near(10));
+        assert_eq!(contract.validators[1].staked, NearToken::from_near(5));
+        assert_eq!(contract.get_staked_for(&guardian), 0);
+        assert_eq!(contract.solvency_ratio(), 8333);
+
+        contract.socialize_loss();
+        assert!(contract.get_shortfall().is_none());
+        assert_eq!(contract.get_staked_for(&accounts(1)), near(5));
+        assert_eq!(contract.get_staked_for(&accounts(2)), near(10));
+        assert_eq!(contract.pool.tickets, NearToken::from_near(15));
+
+        testing_env!(context(&guardian).build());
+        contract.resume_raffles();
+        assert!(!contract.pool.raffles_frozen);
+    }
+
+    #[test]
+    fn test_mul_div() {
+        assert_eq!(mul_div(3, 6, 18), 1);
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+
+        let million_near = NearToken::from_near(1_000_000).as_yoctonear();
+        assert_eq!(
+            mul_div(million_near, million_near / 4, million_near),
+            million_near / 4
+        );
+    }
+
+    fn set_context() {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+    }
+}
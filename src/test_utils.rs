@@ -0,0 +1,37 @@
+// Setup shared by the unit tests
+use crate::*;
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{testing_env, Gas};
+
+impl Contract {
+    // Validator `accounts(0)`, the reserve held by "guardian" and the default config
+    pub(crate) fn for_tests() -> Self {
+        Self::new(
+            accounts(0),
+            "guardian".parse().unwrap(),
+            U64(0),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+    }
+}
+
+// A call from `account` to "contract", tests add the rest of the context
+pub(crate) fn context(account: &AccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .predecessor_account_id(account.clone())
+        .current_account_id("contract".parse().unwrap())
+        .prepaid_gas(Gas::from_tgas(300));
+    builder
+}
+
+pub(crate) fn set_context(account: &AccountId) {
+    testing_env!(context(account).build());
+}
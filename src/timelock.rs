@@ -0,0 +1,336 @@
+use crate::*;
+use near_sdk::{
+    near, require,
+    serde_json::{json, Map, Value},
+};
+
+// Keeps the pending proposals cheap to store and to review
+const MAX_PROPOSALS: usize = 20;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub enum Change {
+    // Fee, deposit limits or a shorter delay
+    Config(ConfigPatch),
+    // Account holding the pool reserve
+    Guardian(AccountId),
+    // Guardian set approving the critical actions
+    Guardians {
+        members: Vec<AccountId>,
+        threshold: u32,
+        approval_window: U64,
+    },
+    AddValidator {
+        account_id: AccountId,
+        weight: u32,
+    },
+    LiquidValidator(Option<AccountId>),
+    Whitelist(Option<AccountId>),
+    Migration {
+        from: AccountId,
+        to: AccountId,
+    },
+    // DAO contract allowed to call `dao_execute`
+    Dao(Option<AccountId>),
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct Proposal {
+    pub id: u64,
+    pub change: Change,
+    pub proposer: AccountId,
+    // Block timestamps (ms)
+    pub proposed_at: u64,
+    pub executable_at: u64,
+}
+
+#[near(serializers=[borsh])]
+#[derive(Default)]
+pub struct Timelock {
+    pub next_id: u64,
+    pub proposals: Vec<Proposal>,
+}
+
+#[near]
+impl Contract {
+    pub fn get_proposals(&self) -> Vec<Proposal> {
+        self.timelock.proposals.clone()
+    }
+
+    pub fn get_proposal(&self, id: u64) -> Option<Proposal> {
+        self.timelock
+            .proposals
+            .iter()
+            .find(|proposal| proposal.id == id)
+            .cloned()
+    }
+
+    // Proposing needs the same role as making the change directly
+    pub fn propose_change(&mut self, change: Change) -> u64 {
+        self.require_change_roles(&change);
+        if let Change::Config(patch) = &change {
+            self.validate_patch(patch.clone());
+        }
+        require!(
+            self.timelock.proposals.len() < MAX_PROPOSALS,
+            "Too many pending proposals"
+        );
+
+        let now = env::block_timestamp_ms();
+        let proposal = Proposal {
+            id: self.timelock.next_id,
+            change,
+            proposer: env::predecessor_account_id(),
+            proposed_at: now,
+            executable_at: now + self.config.timelock_delay,
+        };
+
+        self.timelock.next_id += 1;
+        self.timelock.proposals.push(proposal.clone());
+        self.record_admin_action("propose_change", Value::Null, json!(proposal));
+        self.log_proposal("change_proposed", &proposal);
+
+        proposal.id
+    }
+
+    // Anyone can execute a proposal once its delay passed
+    pub fn execute_proposal(&mut self, id: u64) {
+        let proposal = self.take_proposal(id);
+        require!(
+            env::block_timestamp_ms() >= proposal.executable_at,
+            "The proposal is still timelocked"
+        );
+
+        let (mut old, mut new) = self.apply_change(proposal.change.clone());
+        old.insert("proposal".to_string(), json!(id));
+        new.insert("proposal".to_string(), json!(id));
+        self.record_admin_action("execute_proposal", Value::Object(old), Value::Object(new));
+        self.log_proposal("change_executed", &proposal);
+    }
+
+    pub fn cancel_proposal(&mut self, id: u64) {
+        self.require_role(&[Role::Guardian]);
+
+        let proposal = self.take_proposal(id);
+        self.record_admin_action("cancel_proposal", json!(proposal), Value::Null);
+        self.log_proposal("change_cancelled", &proposal);
+    }
+
+    fn take_proposal(&mut self, id: u64) -> Proposal {
+        let idx = self
+            .timelock
+            .proposals
+            .iter()
+            .position(|proposal| proposal.id == id)
+            .expect("Proposal not found");
+
+        self.timelock.proposals.remove(idx)
+    }
+
+    pub(crate) fn require_change_roles(&self, change: &Change) {
+        match change {
+            Change::Config(patch) => self.require_patch_roles(patch),
+            Change::Migration { .. } => self.require_role(&[Role::Operator]),
+            Change::Guardian(_)
+            | Change::Guardians { .. }
+            | Change::AddValidator { .. }
+            | Change::LiquidValidator(_)
+            | Change::Whitelist(_)
+            | Change::Dao(_) => self.require_role(&[Role::Owner]),
+        }
+    }
+
+    // The checks of each change run now, the state might have moved since the proposal.
+    // Returns the old and new values for the audit log
+    pub(crate) fn apply_change(
+        &mut self,
+        change: Change,
+    ) -> (Map<String, Value>, Map<String, Value>) {
+        let (old, new) = match change {
+            Change::Config(patch) => self.apply_config(patch),
+            Change::Guardian(account_id) => {
+                let old = json!({ "guardian": &self.config.guardian });
+                self.replace_guardian(account_id.clone());
+                (old, json!({ "guardian": account_id }))
+            }
+            Change::Guardians {
+                members,
+                threshold,
+                approval_window,
+            } => {
+                let old = json!({
+                    "guardians": &self.guardians.members,
+                    "threshold": self.guardians.threshold,
+                    "approval_window": U64(self.guardians.approval_window),
+                });
+                self.replace_guardians(members.clone(), threshold, approval_window.0);
+                let new = json!({
+                    "guardians": members,
+                    "threshold": threshold,
+                    "approval_window": approval_window,
+                });
+                (old, new)
+            }
+            Change::AddValidator { account_id, weight } => {
+                let new = json!({ "validator": { "account_id": &account_id, "weight": weight } });
+                self.insert_validator(account_id, weight);
+                (json!({ "validator": null }), new)
+            }
+            Change::LiquidValidator(account_id) => {
+                let liquid = self
+                    .validators
+                    .iter()
+                    .find(|validator| validator.liquid)
+                    .map(|validator| validator.account_id.clone());
+                self.apply_liquid_validator(account_id.clone());
+                (
+                    json!({ "liquid_validator": liquid }),
+                    json!({ "liquid_validator": account_id }),
+                )
+            }
+            Change::Whitelist(whitelist) => {
+                let old = json!({ "whitelist": &self.config.whitelist });
+                self.apply_whitelist(whitelist.clone());
+                (old, json!({ "whitelist": whitelist }))
+            }
+            // The guardians still have to approve it
+            Change::Migration { from, to } => {
+                let id = self.open_action(CriticalAction::Migration { from, to });
+                (json!({ "action": null }), json!({ "action": id }))
+            }
+            Change::Dao(dao) => {
+                let old = json!({ "dao": &self.config.dao });
+                self.config.dao = dao.clone();
+                (old, json!({ "dao": dao }))
+            }
+        };
+
+        match (old, new) {
+            (Value::Object(old), Value::Object(new)) => (old, new),
+            _ => unreachable!(),
+        }
+    }
+
+    // The old guardian keeps its tickets as a regular user, and
+    // the new one takes its seat in the guardian set if it had one
+    fn replace_guardian(&mut self, account_id: AccountId) {
+        let old = std::mem::replace(&mut self.config.guardian, account_id.clone());
+
+        if let Some(idx) = self
+            .guardians
+            .members
+            .iter()
+            .position(|member| member == &old)
+        {
+            require!(
+                !self.guardians.members.contains(&account_id),
+                "Already a guardian"
+            );
+            self.guardians.members[idx] = account_id.clone();
+            for pending in self.guardians.pending.iter_mut() {
+                pending.approvals.retain(|approval| approval != &old);
+            }
+            self.revoke_role_from(&Role::Guardian, &old);
+            self.grant_role_to(Role::Guardian, &account_id);
+        }
+
+        if !self.is_registered(&account_id) {
+            self.add_new_user(&account_id);
+        }
+    }
+
+    fn log_proposal(&self, event: &str, proposal: &Proposal) {
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": event,
+            "data": {
+                "proposal": proposal,
+                "by": env::predecessor_account_id(),
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    const DELAY: u64 = TIMELOCK_DELAY;
+
+    #[test]
+    fn test_timelocked_change() {
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let fee = contract.set_pool_fee(10);
+        let guardian = contract.propose_change(Change::Guardian(accounts(2)));
+        assert_eq!(contract.get_proposals().len(), 2);
+        assert_eq!(contract.get_proposal(fee).unwrap().executable_at, DELAY);
+
+        set_context(&accounts(1), DELAY);
+        contract.execute_proposal(fee);
+        assert_eq!(contract.pool.pool_fee, 10);
+
+        // The guardian can stop a change before it happens
+        set_context(&"guardian".parse().unwrap(), DELAY);
+        contract.cancel_proposal(guardian);
+        assert!(contract.get_proposals().is_empty());
+        assert_eq!(
+            contract.config.guardian,
+            "guardian".parse::<AccountId>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_replace_guardian() {
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let id = contract.propose_change(Change::Guardian(accounts(2)));
+        set_context(&"contract".parse().unwrap(), DELAY);
+        contract.execute_proposal(id);
+
+        assert_eq!(contract.config.guardian, accounts(2));
+        assert!(contract.has_role(accounts(2), Role::Guardian));
+        assert!(!contract.has_role("guardian".parse().unwrap(), Role::Guardian));
+        assert!(contract.is_registered(&accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The proposal is still timelocked")]
+    fn test_proposal_waits_delay() {
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        let id = contract.add_validator(accounts(1), 1);
+
+        set_context(&"contract".parse().unwrap(), DELAY - 1);
+        contract.execute_proposal(id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sensitive changes are timelocked, use propose_change")]
+    fn test_sensitive_config_needs_proposal() {
+        set_context(&"contract".parse().unwrap(), 0);
+        let mut contract = Contract::for_tests();
+
+        contract.update_config(ConfigPatch {
+            max_deposit: Some(NearToken::from_near(5)),
+            ..Default::default()
+        });
+    }
+
+    fn set_context(account: &AccountId, timestamp_ms: u64) {
+        testing_env!(context(account)
+            .block_timestamp(timestamp_ms * 1_000_000)
+            .build());
+    }
+}
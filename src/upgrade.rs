@@ -0,0 +1,353 @@
+use crate::users::{User, UserNode};
+use crate::*;
+use near_sdk::{near, require, serde_json::json, store::LookupMap, Gas, Promise, PromiseOrValue};
+
+// State layout of the contract before the upgrade
+#[near(serializers=[borsh])]
+pub struct OldConfig {
+    external_pool: AccountId,
+    min_to_raffle: NearToken,
+    max_to_raffle: NearToken,
+    min_deposit: NearToken,
+    max_deposit: NearToken,
+    epochs_wait: u64,
+    time_between_raffles: u64,
+    guardian: AccountId,
+    emergency: bool,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldPool {
+    to_unstake: NearToken,
+    prize: NearToken,
+    last_prize_update: u64,
+    pool_fee: u8,
+    next_raffle: u64,
+    tickets: NearToken,
+    is_interacting: bool,
+    next_withdraw_turn: u64,
+    next_withdraw_epoch: u64,
+    winners: Vec<(AccountId, NearToken)>,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldUser {
+    node: u32,
+    unstaked: u128,
+    withdraw_turn: Option<u64>,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldUsers {
+    map: LookupMap<AccountId, OldUser>,
+    tree: Vector<UserNode>,
+}
+
+#[near(serializers=[borsh])]
+pub struct OldContract {
+    config: OldConfig,
+    pool: OldPool,
+    users: OldUsers,
+    next_action: Action,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct StateUpgrade {
+    // Node of the first user still in the old layout
+    pub next_user: u32,
+}
+
+#[near]
+impl Contract {
+    // Runs right after the new code is deployed. The users are too many for
+    // one call, they are converted by `migrate_users` while the pool waits
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract = env::state_read().expect("No state to migrate");
+        require!(
+            !old.pool.is_interacting,
+            "Wait for the external pool interaction to finish"
+        );
+
+        let OldContract {
+            config,
+            pool,
+            users: old_users,
+            next_action,
+        } = old;
+
+        let mut contract = Self::new(
+            config.external_pool,
+            config.guardian,
+            U64(pool.next_raffle),
+            Some(config.min_to_raffle),
+            Some(config.max_to_raffle),
+            Some(config.min_deposit),
+            Some(config.max_deposit),
+            Some(config.epochs_wait),
+            Some(U64(config.time_between_raffles)),
+            None,
+            None,
+        );
+
+        if config.emergency {
+            contract.config.paused = Paused::all();
+        }
+
+        // The tree keeps its layout, only the users' entries gain new fields
+        contract.users.tree = old_users.tree;
+        contract.upgrade = Some(StateUpgrade { next_user: 0 });
+
+        contract.pool.to_unstake = pool.to_unstake;
+        contract.pool.prize = pool.prize;
+        contract.pool.last_prize_update = pool.last_prize_update;
+        contract.pool.pool_fee = pool.pool_fee;
+        contract.pool.tickets = pool.tickets;
+        contract.pool.next_withdraw_turn = pool.next_withdraw_turn;
+        contract.pool.next_withdraw_epoch = pool.next_withdraw_epoch;
+        contract.pool.winners = pool.winners;
+        contract.next_action = next_action;
+
+        // The only validator holds every ticket, what it is unstaking is read
+        // from it once the users are converted
+        contract.validators[0].staked = pool.tickets;
+
+        contract
+    }
+
+    pub fn get_state_upgrade(&self) -> Option<StateUpgrade> {
+        self.upgrade.clone()
+    }
+
+    // Converts the next `limit` users. After the last one, it asks the validator
+    // what it is unstaking for us and the pool opens again
+    pub fn migrate_users(&mut self, limit: u32) -> PromiseOrValue<bool> {
+        let upgrade = self.upgrade.clone().expect("Nothing to migrate");
+
+        let total = self.users.tree.len();
+        let end = upgrade.next_user.saturating_add(limit).min(total);
+
+        // Entries not converted yet are read in the old layout
+        let old_users: LookupMap<AccountId, OldUser> = LookupMap::new(StorageKey::Users);
+        let mut users_unstaked: u128 = 0;
+        for node in upgrade.next_user..end {
+            let account_id = self.users.tree[node].account_id.clone();
+            let Some(user) = old_users.get(&account_id) else {
+                continue;
+            };
+
+            users_unstaked += user.unstaked;
+            // `set` overwrites the old entry without reading it
+            self.users.map.set(
+                account_id,
+                Some(User {
+                    node: user.node,
+                    unstaked: user.unstaked,
+                    withdraw_turn: user.withdraw_turn,
+                    pending_deposit: 0,
+                }),
+            );
+        }
+
+        self.pool.users_unstaked = self
+            .pool
+            .users_unstaked
+            .saturating_add(NearToken::from_yoctonear(users_unstaked));
+        self.upgrade = Some(StateUpgrade { next_user: end });
+
+        if end < total {
+            return PromiseOrValue::Value(false);
+        }
+
+        require!(
+            env::prepaid_gas().ge(&Gas::from_tgas(50)),
+            "Please use at least 50Tgas"
+        );
+
+        let validator = self.validators[0].account_id.clone();
+        let (entries, query) =
+            self.query_accounts(std::slice::from_ref(&validator), Gas::from_tgas(10));
+
+        PromiseOrValue::Promise(
+            query.then(
+                Promise::new(env::current_account_id()).function_call(
+                    "migrate_users_callback".to_string(),
+                    json!({ "validator": validator, "entries": entries })
+                        .to_string()
+                        .into_bytes(),
+                    NO_DEPOSIT,
+                    Gas::from_tgas(20),
+                ),
+            ),
+        )
+    }
+
+    #[private]
+    pub fn migrate_users_callback(&mut self, validator: AccountId, entries: Vec<u64>) -> bool {
+        let Some(external_user) = self.read_accounts(&entries).remove(0) else {
+            // The users are converted, calling `migrate_users` again retries the query
+            log!("Failed to query the external pool {}", validator);
+            return false;
+        };
+
+        let idx = self.validator_index(&validator);
+        self.validators[idx].unstaking = external_user.unstaked_balance;
+        self.upgrade = None;
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": "state_upgraded",
+            "data": {
+                "users": self.users.tree.len(),
+                "users_unstaked": &self.pool.users_unstaked,
+                "unstaking": &external_user.unstaked_balance,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ExternalUser;
+    use crate::test_utils::{context, set_context};
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{serde_json, test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    fn near(amount: u128) -> NearToken {
+        NearToken::from_near(amount)
+    }
+
+    #[test]
+    fn test_migrate() {
+        set_context(&"contract".parse().unwrap());
+
+        let mut users = OldUsers {
+            map: LookupMap::new(StorageKey::Users),
+            tree: Vector::new(StorageKey::Tree),
+        };
+        for (node, (account_id, staked, unstaked)) in [
+            ("guardian", near(1), near(0)),
+            ("ana", near(5), near(2)),
+            ("bob", near(3), near(1)),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let account_id: AccountId = account_id.parse().unwrap();
+            users.map.insert(
+                account_id.clone(),
+                OldUser {
+                    node: node as u32,
+                    unstaked: unstaked.as_yoctonear(),
+                    withdraw_turn: Some(2),
+                },
+            );
+            users.tree.push(UserNode {
+                account_id,
+                weight: staked.as_yoctonear(),
+                staked: staked.as_yoctonear(),
+            });
+        }
+        users.map.flush();
+        users.tree.flush();
+
+        let pool = OldPool {
+            to_unstake: near(1),
+            prize: near(1),
+            last_prize_update: 0,
+            pool_fee: 2,
+            next_raffle: 10,
+            tickets: near(9),
+            is_interacting: false,
+            next_withdraw_turn: 3,
+            next_withdraw_epoch: 5,
+            winners: vec![],
+        };
+
+        env::state_write(&OldContract {
+            config: OldConfig {
+                external_pool: accounts(0),
+                min_to_raffle: MIN_TO_RAFFLE,
+                max_to_raffle: MAX_TO_RAFFLE,
+                min_deposit: MIN_DEPOSIT,
+                max_deposit: MAX_DEPOSIT,
+                epochs_wait: EPOCHS_WAIT,
+                time_between_raffles: 1000,
+                guardian: "guardian".parse().unwrap(),
+                emergency: true,
+            },
+            pool,
+            users,
+            next_action: Action::Withdraw,
+        });
+
+        let mut contract = Contract::migrate();
+
+        assert_eq!(contract.config.paused, Paused::all());
+        assert_eq!(contract.pool.tickets, near(9));
+        assert_eq!(contract.pool.prize, near(1));
+        assert_eq!(contract.pool.next_withdraw_turn, 3);
+        assert_eq!(contract.next_action, Action::Withdraw);
+        assert_eq!(contract.validators[0].staked, near(9));
+
+        // The users are converted a page at a time
+        assert!(matches!(
+            contract.migrate_users(2),
+            PromiseOrValue::Value(false)
+        ));
+        assert_eq!(contract.get_state_upgrade().unwrap().next_user, 2);
+        assert_eq!(contract.pool.users_unstaked, near(2));
+        assert!(matches!(
+            contract.migrate_users(2),
+            PromiseOrValue::Promise(_)
+        ));
+        assert_eq!(contract.pool.users_unstaked, near(3));
+
+        // The validator reports what it is unstaking
+        testing_env!(
+            context(&"contract".parse().unwrap()).build(),
+            test_vm_config(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(
+                serde_json::to_vec(&ExternalUser {
+                    account_id: "contract".parse().unwrap(),
+                    unstaked_balance: near(2),
+                    staked_balance: near(10),
+                    can_withdraw: false,
+                })
+                .unwrap()
+            )],
+        );
+        assert!(contract.migrate_users_callback(accounts(0), vec![0]));
+        assert!(contract.get_state_upgrade().is_none());
+        assert_eq!(contract.validators[0].unstaking, near(2));
+
+        let ana = contract.get_user(&"ana".parse().unwrap());
+        assert_eq!(ana.unstaked, near(2).as_yoctonear());
+        assert_eq!(ana.withdraw_turn, Some(2));
+        assert_eq!(ana.pending_deposit, 0);
+        assert_eq!(
+            contract.get_staked_for(&"bob".parse().unwrap()),
+            near(3).as_yoctonear()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The state is being upgraded, we will be back soon")]
+    fn test_pool_waits_for_the_users() {
+        set_context(&"contract".parse().unwrap());
+        let mut contract = Contract::for_tests();
+        contract.upgrade = Some(StateUpgrade { next_user: 0 });
+
+        contract.require_not_paused(PauseFlag::Deposit);
+    }
+}
@@ -1,5 +1,13 @@
+// Kept as in the original code, its lints are not fixed here
+#![allow(
+    clippy::needless_borrow,
+    clippy::needless_return,
+    clippy::unnecessary_cast,
+    clippy::needless_range_loop
+)]
+
 use crate::*;
-use near_sdk::{json_types::U128, near, store::LookupMap, BorshStorageKey, NearToken};
+use near_sdk::{json_types::U128, near, store::LookupMap, NearToken};
 
 #[near(serializers=[borsh, json])]
 #[derive(Clone)]
@@ -11,6 +19,7 @@ pub struct User {
     pub node: u32,
     pub unstaked: u128,
     pub withdraw_turn: Option<u64>,
+    pub pending_deposit: u128,
 }
 
 #[near(serializers=[borsh, json])]
@@ -27,13 +36,6 @@ pub struct Users {
     pub tree: Vector<UserNode>,
 }
 
-#[near(serializers = [borsh])]
-#[derive(BorshStorageKey)]
-enum StorageKey {
-    Users,
-    Tree,
-}
-
 impl Default for Users {
     fn default() -> Self {
         Self {
@@ -73,6 +75,7 @@ impl Contract {
                 node: uid,
                 unstaked: 0,
                 withdraw_turn: None,
+                pending_deposit: 0,
             },
         );
 
@@ -103,6 +106,7 @@ impl Contract {
         let current_user = self.users.map.get_mut(user).expect("User not found!");
 
         current_user.unstaked += amount.as_yoctonear();
+        self.pool.users_unstaked = self.pool.users_unstaked.saturating_add(amount);
     }
 
     pub(crate) fn withdraw_all_for(&mut self, user: &AccountId) -> u128 {
@@ -112,19 +116,37 @@ impl Contract {
 
         current_user.unstaked = 0;
 
+        self.pool.users_unstaked = self
+            .pool
+            .users_unstaked
+            .saturating_sub(NearToken::from_yoctonear(unstaked_balance));
         unstaked_balance
     }
 
+    pub(crate) fn add_pending_deposit_for(&mut self, user: &AccountId, amount: NearToken) {
+        let current_user = self.users.map.get_mut(user).expect("User not found!");
+        current_user.pending_deposit += amount.as_yoctonear();
+
+        self.pool.pending_deposits = self.pool.pending_deposits.saturating_add(amount);
+    }
+
+    pub(crate) fn remove_pending_deposit_for(&mut self, user: &AccountId, amount: NearToken) {
+        let current_user = self.users.map.get_mut(user).expect("User not found!");
+        current_user.pending_deposit = current_user
+            .pending_deposit
+            .saturating_sub(amount.as_yoctonear());
+
+        self.pool.pending_deposits = self.pool.pending_deposits.saturating_sub(amount);
+    }
+
     pub(crate) fn set_withdraw_turn_for(&mut self, user: &AccountId, turn: u64) {
         let user = self.users.map.get_mut(user).expect("User not found!");
         user.withdraw_turn = Some(turn)
     }
 
-    fn remove_tickets_from(&mut self, user: &AccountId, amount: u128) {
-        self.pool
-            .tickets
-            .saturating_sub(NearToken::from_yoctonear(amount));
-
+    // Only updates the users, `pool.tickets` tracks what is staked in the
+    // validators and each caller updates it when the funds actually leave
+    pub(crate) fn remove_tickets_from(&mut self, user: &AccountId, amount: u128) {
         let mut uid = self.users.map[user].node;
         self.users.tree[uid].staked -= amount;
         self.users.tree[uid].weight -= amount;
@@ -200,30 +222,27 @@ impl Contract {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::context;
 
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::{testing_env, Gas};
-    use near_sdk::json_types::U64;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{testing_env, PromiseError};
 
     #[test]
     fn test_user_handling() {
         let guardian: AccountId = "guardian".parse().unwrap();
-        let mut contract = Contract::new(
-            accounts(0),
-            guardian.clone(),
-            U64(env::block_timestamp_ms()),
-            None,
-            None,
-            Some(NearToken::from_yoctonear(1)),
-            None,
-            None,
-            None,
-        );
+        let mut contract = Contract::for_tests();
+        contract.config.min_deposit = NearToken::from_yoctonear(1);
 
         set_context(&guardian, NearToken::from_yoctonear(1));
         contract.deposit_and_stake();
 
-        contract.deposit_and_stake_callback(Ok(()), guardian.clone(), NearToken::from_yoctonear(1));
+        contract.deposit_and_stake_callback(
+            Ok(()),
+            guardian.clone(),
+            NearToken::from_yoctonear(1),
+            accounts(0),
+            contract.journal.next_id - 1,
+        );
 
         for i in 1..3 {
             set_context(
@@ -239,6 +258,8 @@ mod tests {
                 Ok(()),
                 format!("user{}", i).parse().unwrap(),
                 NearToken::from_yoctonear((1 + i) as u128),
+                accounts(0),
+                contract.journal.next_id - 1,
             );
         }
 
@@ -250,24 +271,65 @@ mod tests {
     }
 
     #[test]
-    fn test_users_tree() {
+    fn test_pending_deposits() {
         let guardian: AccountId = "guardian".parse().unwrap();
-        let mut contract = Contract::new(
-            accounts(0),
+        let user: AccountId = "user".parse().unwrap();
+        let mut contract = Contract::for_tests();
+        contract.config.min_deposit = NearToken::from_yoctonear(1);
+
+        set_context(&guardian, NearToken::from_yoctonear(1));
+        contract.deposit_and_stake();
+        set_context(&user, NearToken::from_yoctonear(5));
+        contract.deposit_and_stake();
+
+        // Nothing is credited until the callbacks run
+        assert_eq!(contract.pool.tickets, NearToken::from_yoctonear(0));
+        assert_eq!(contract.pool.pending_deposits, NearToken::from_yoctonear(6));
+        assert_eq!(
+            contract.get_user_info(user.clone()).pending_deposit,
+            NearToken::from_yoctonear(5)
+        );
+
+        set_context(&"contract".parse().unwrap(), NearToken::from_yoctonear(0));
+        contract.deposit_and_stake_callback(
+            Ok(()),
             guardian.clone(),
-            U64(env::block_timestamp_ms()),
-            None,
-            None,
-            Some(NearToken::from_yoctonear(1)),
-            None,
-            None,
-            None,
+            NearToken::from_yoctonear(1),
+            accounts(0),
+            0,
+        );
+        contract.deposit_and_stake_callback(
+            Err(PromiseError::Failed),
+            user.clone(),
+            NearToken::from_yoctonear(5),
+            accounts(0),
+            1,
         );
 
+        assert_eq!(contract.pool.tickets, NearToken::from_yoctonear(1));
+        assert_eq!(contract.pool.pending_deposits, NearToken::from_yoctonear(0));
+
+        let user_info = contract.get_user_info(user);
+        assert_eq!(user_info.pending_deposit, NearToken::from_yoctonear(0));
+        assert_eq!(user_info.staked, NearToken::from_yoctonear(0));
+    }
+
+    #[test]
+    fn test_users_tree() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let mut contract = Contract::for_tests();
+        contract.config.min_deposit = NearToken::from_yoctonear(1);
+
         set_context(&guardian, NearToken::from_yoctonear(1));
         contract.deposit_and_stake();
 
-        contract.deposit_and_stake_callback(Ok(()), guardian.clone(), NearToken::from_yoctonear(1));
+        contract.deposit_and_stake_callback(
+            Ok(()),
+            guardian.clone(),
+            NearToken::from_yoctonear(1),
+            accounts(0),
+            contract.journal.next_id - 1,
+        );
 
         for i in 1..10 {
             set_context(
@@ -283,6 +345,8 @@ mod tests {
                 Ok(()),
                 format!("user{}", i).parse().unwrap(),
                 NearToken::from_yoctonear((1 + i) as u128),
+                accounts(0),
+                contract.journal.next_id - 1,
             );
         }
 
@@ -294,16 +358,26 @@ mod tests {
         // Modify participants weights
         set_context(&"contract".parse().unwrap(), NearToken::from_near(1));
 
+        set_context(&"user5".parse().unwrap(), NearToken::from_yoctonear(2));
+        contract.deposit_and_stake();
+        set_context(&"contract".parse().unwrap(), NearToken::from_near(1));
         contract.deposit_and_stake_callback(
             Ok(()),
             "user5".parse().unwrap(),
             NearToken::from_yoctonear(2),
+            accounts(0),
+            contract.journal.next_id - 1,
         );
 
+        set_context(&"user7".parse().unwrap(), NearToken::from_yoctonear(1));
+        contract.deposit_and_stake();
+        set_context(&"contract".parse().unwrap(), NearToken::from_near(1));
         contract.deposit_and_stake_callback(
             Ok(()),
             "user7".parse().unwrap(),
             NearToken::from_yoctonear(1),
+            accounts(0),
+            contract.journal.next_id - 1,
         );
 
         assert!(weights_equal(
@@ -311,10 +385,15 @@ mod tests {
             &[58, 39, 18, 22, 15, 8, 7, 9, 9, 10]
         ));
 
+        set_context(&"user3".parse().unwrap(), NearToken::from_yoctonear(3));
+        contract.deposit_and_stake();
+        set_context(&"contract".parse().unwrap(), NearToken::from_near(1));
         contract.deposit_and_stake_callback(
             Ok(()),
             "user3".parse().unwrap(),
             NearToken::from_yoctonear(3),
+            accounts(0),
+            contract.journal.next_id - 1,
         );
 
         assert!(weights_equal(
@@ -322,7 +401,16 @@ mod tests {
             &[61, 42, 18, 25, 15, 8, 7, 9, 9, 10]
         ));
 
-        contract.deposit_and_stake_callback(Ok(()), guardian.clone(), NearToken::from_yoctonear(1));
+        set_context(&guardian, NearToken::from_yoctonear(1));
+        contract.deposit_and_stake();
+        set_context(&"contract".parse().unwrap(), NearToken::from_near(1));
+        contract.deposit_and_stake_callback(
+            Ok(()),
+            guardian.clone(),
+            NearToken::from_yoctonear(1),
+            accounts(0),
+            contract.journal.next_id - 1,
+        );
         assert!(weights_equal(
             &contract,
             &[62, 42, 18, 25, 15, 8, 7, 9, 9, 10]
@@ -360,16 +448,73 @@ mod tests {
         assert_eq!(contract.find_user_with_ticket(11u128), 7);
     }
 
+    #[test]
+    fn test_withdrawal_wait() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+        let mut contract = Contract::for_tests();
+
+        contract.add_new_user(&guardian);
+        contract.stake_tickets_for(&guardian, 1);
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, NearToken::from_yoctonear(0));
+        contract.unstake(NearToken::from_yoctonear(4));
+
+        // Both views read the same wait, in epochs
+        let check = |contract: &Contract, available: bool, epochs: u64| {
+            let info = contract.get_user_info(user.clone());
+            let status = contract.get_withdrawal_status(user.clone());
+            assert_eq!((info.available, info.withdraw_turn), (available, epochs));
+            assert_eq!((status.available, status.epochs_remaining), (available, epochs));
+        };
+        check(&contract, false, contract.config.epochs_wait);
+
+        // The unstake turn ran in epoch 1, the funds unlock in epoch 5
+        contract.pool.next_withdraw_turn = 2;
+        contract.pool.next_withdraw_epoch = 5;
+        contract.next_action = Action::Withdraw;
+        testing_env!(context(&user).epoch_height(1).build());
+        check(&contract, false, 4);
+
+        // Unlocked, but still in the validator until the withdraw turn runs
+        testing_env!(context(&user).epoch_height(5).build());
+        check(&contract, false, 0);
+
+        contract.pool.next_withdraw_turn = 3;
+        contract.next_action = Action::Unstake;
+        check(&contract, true, 0);
+
+        contract.withdraw_all();
+        assert_eq!(contract.get_user_info(user).unstaked, NearToken::from_yoctonear(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdraw not ready")]
+    fn test_withdraw_before_the_turn() {
+        let guardian: AccountId = "guardian".parse().unwrap();
+        let user: AccountId = "user".parse().unwrap();
+        let mut contract = Contract::for_tests();
+
+        contract.add_new_user(&guardian);
+        contract.stake_tickets_for(&guardian, 1);
+        contract.add_new_user(&user);
+        contract.stake_tickets_for(&user, 10);
+
+        set_context(&user, NearToken::from_yoctonear(0));
+        contract.unstake(NearToken::from_yoctonear(4));
+
+        // Unstaked from the validator, the withdraw turn did not run yet
+        contract.pool.next_withdraw_turn = 2;
+        contract.withdraw_all();
+    }
+
     fn set_context(account: &AccountId, attached_deposit: NearToken) {
-        let context = VMContextBuilder::new()
+        testing_env!(context(account)
             .account_balance(NearToken::from_near(20))
-            .predecessor_account_id(account.clone())
-            .current_account_id("contract".parse().unwrap())
             .attached_deposit(attached_deposit)
-            .prepaid_gas(Gas::from_tgas(300))
-            .build();
-
-        testing_env!(context);
+            .build());
     }
 
     fn weights_equal(contract: &Contract, expected_weights: &[u128]) -> bool {
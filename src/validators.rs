@@ -0,0 +1,339 @@
+use crate::shortfall::mul_div;
+use crate::*;
+use near_sdk::{
+    near, require,
+    serde_json::{json, Value},
+};
+
+// Maximum number of external pools, every turn calls all of them in a single transaction
+const MAX_VALIDATORS: usize = 4;
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, Debug)]
+pub struct Validator {
+    pub account_id: AccountId,
+    // Target allocation, relative to the weights of the other validators
+    pub weight: u32,
+    // Tickets staked in this validator
+    pub staked: NearToken,
+    // Unstaked from this validator, waiting to be withdrawn
+    pub unstaking: NearToken,
+    // Confirmed by the whitelist, if there is one
+    pub verified: bool,
+    // Liquid staking contract, its shares can be swapped for an instant exit
+    pub liquid: bool,
+}
+
+impl Validator {
+    pub(crate) fn new(account_id: AccountId, weight: u32) -> Self {
+        Self {
+            account_id,
+            weight,
+            staked: NearToken::from_yoctonear(0),
+            unstaking: NearToken::from_yoctonear(0),
+            verified: true,
+            liquid: false,
+        }
+    }
+}
+
+#[near]
+impl Contract {
+    pub fn get_validators(&self) -> Vec<Validator> {
+        self.validators.clone()
+    }
+
+    // Timelocked, returns the id of the proposal
+    pub fn add_validator(&mut self, account_id: AccountId, weight: u32) -> u64 {
+        self.propose_change(Change::AddValidator { account_id, weight })
+    }
+
+    pub(crate) fn insert_validator(&mut self, account_id: AccountId, weight: u32) {
+        require!(
+            self.validators.len() < MAX_VALIDATORS,
+            format!("Cannot have more than {} validators", MAX_VALIDATORS)
+        );
+        require!(
+            self.find_validator(&account_id).is_none(),
+            "Validator already added"
+        );
+
+        self.add_unverified_validator(account_id, weight);
+    }
+
+    pub fn set_validator_weight(&mut self, account_id: AccountId, weight: u32) {
+        self.require_role(&[Role::Operator]);
+        let idx = self.validator_index(&account_id);
+        let old = self.validators[idx].weight;
+        self.validators[idx].weight = weight;
+
+        require!(
+            self.validators.iter().any(|validator| validator.weight > 0),
+            "At least one validator needs a positive weight"
+        );
+
+        self.record_admin_action(
+            "set_validator_weight",
+            json!({ "account_id": &account_id, "weight": old }),
+            json!({ "account_id": &account_id, "weight": weight }),
+        );
+    }
+
+    pub fn remove_validator(&mut self, account_id: AccountId) {
+        self.require_role(&[Role::Operator]);
+        let idx = self.validator_index(&account_id);
+        let validator = &self.validators[idx];
+
+        require!(
+            validator.staked.is_zero() && validator.unstaking.is_zero(),
+            "Validator still holds funds"
+        );
+
+        let validator = self.validators.remove(idx);
+
+        require!(
+            self.validators.iter().any(|validator| validator.weight > 0),
+            "At least one validator needs a positive weight"
+        );
+
+        self.record_admin_action("remove_validator", json!(validator), Value::Null);
+    }
+
+    pub(crate) fn find_validator(&self, account_id: &AccountId) -> Option<usize> {
+        self.validators
+            .iter()
+            .position(|validator| &validator.account_id == account_id)
+    }
+
+    pub(crate) fn validator_index(&self, account_id: &AccountId) -> usize {
+        self.find_validator(account_id)
+            .expect("Validator not found")
+    }
+
+    // Target stake of each validator for a given total, following their weights
+    fn targets_for(&self, total: u128) -> Vec<u128> {
+        let total_weight: u128 = self.validators.iter().map(|v| v.weight as u128).sum();
+
+        self.validators
+            .iter()
+            .map(|validator| total / total_weight.max(1) * validator.weight as u128)
+            .collect()
+    }
+
+    fn total_staked_in_validators(&self) -> u128 {
+        self.validators
+            .iter()
+            .map(|validator| validator.staked.as_yoctonear())
+            .sum()
+    }
+
+    // Deposits go to the validator furthest below its target allocation
+    pub(crate) fn validator_for_deposit(&self, amount: NearToken) -> AccountId {
+        let total = self.total_staked_in_validators() + amount.as_yoctonear();
+        let targets = self.targets_for(total);
+
+        let (idx, _) = self
+            .validators
+            .iter()
+            .enumerate()
+            .filter(|(idx, validator)| targets[*idx] > 0 && validator.verified)
+            .max_by_key(|(idx, validator)| {
+                targets[*idx].saturating_sub(validator.staked.as_yoctonear())
+            })
+            .expect("No validator available");
+
+        self.validators[idx].account_id.clone()
+    }
+
+    // Raffled prizes become tickets, each validator earned its part of them
+    pub(crate) fn credit_validators(&mut self, amount: NearToken) {
+        let total = self.total_staked_in_validators();
+        if total == 0 {
+            return;
+        }
+
+        let mut remaining = amount.as_yoctonear();
+        for validator in self.validators.iter_mut() {
+            let share = mul_div(
+                amount.as_yoctonear(),
+                validator.staked.as_yoctonear(),
+                total,
+            )
+            .min(remaining);
+            validator.staked = validator
+                .staked
+                .saturating_add(NearToken::from_yoctonear(share));
+            remaining -= share;
+        }
+
+        // Rounding leftovers go to the first validator holding funds
+        if let Some(validator) = self.validators.iter_mut().find(|v| !v.staked.is_zero()) {
+            validator.staked = validator
+                .staked
+                .saturating_add(NearToken::from_yoctonear(remaining));
+        }
+    }
+
+    // Splits an unstake between validators, drawing from the most over-allocated first
+    pub(crate) fn split_unstake(&self, amount: NearToken) -> Vec<(AccountId, NearToken)> {
+        // The validator we are migrating from is left to the migration
+        let stakes: Vec<u128> = self
+            .validators
+            .iter()
+            .map(
+                |validator| match self.is_migrating_from(&validator.account_id) {
+                    true => 0,
+                    false => validator.staked.as_yoctonear(),
+                },
+            )
+            .collect();
+
+        let total: u128 = stakes.iter().sum();
+        let targets = self.targets_for(total.saturating_sub(amount.as_yoctonear()));
+
+        let mut excess: Vec<(usize, u128)> = stakes
+            .iter()
+            .enumerate()
+            .map(|(idx, staked)| (idx, staked.saturating_sub(targets[idx])))
+            .collect();
+        excess.sort_by_key(|(_, over)| std::cmp::Reverse(*over));
+
+        let mut remaining = amount.as_yoctonear();
+        let mut shares = vec![0u128; self.validators.len()];
+
+        for (idx, over) in excess.iter() {
+            let share = remaining.min(*over);
+            shares[*idx] += share;
+            remaining -= share;
+        }
+
+        // Rounding leftovers come from whoever still has stake
+        for (idx, staked) in stakes.iter().enumerate() {
+            let available = staked - shares[idx];
+            let share = remaining.min(available);
+            shares[idx] += share;
+            remaining -= share;
+        }
+
+        self.validators
+            .iter()
+            .zip(shares)
+            .filter(|(_, share)| *share > 0)
+            .map(|(validator, share)| {
+                (
+                    validator.account_id.clone(),
+                    NearToken::from_yoctonear(share),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{testing_env, PromiseError};
+
+    #[test]
+    fn test_validator_allocation() {
+        set_context();
+        let mut contract = Contract::for_tests();
+        contract.set_validator_weight(accounts(0), 1);
+        contract.insert_validator(accounts(1), 3);
+
+        // Deposits fill the validator furthest below its target
+        let near = |amount: u128| NearToken::from_near(amount);
+        assert_eq!(contract.validator_for_deposit(near(4)), accounts(1));
+
+        contract.validators[0].staked = near(4);
+        contract.validators[1].staked = near(4);
+        assert_eq!(contract.validator_for_deposit(near(4)), accounts(1));
+
+        // Unstakes drain the most over-allocated validator first
+        assert_eq!(
+            contract.split_unstake(near(2)),
+            vec![(accounts(0), near(2))]
+        );
+        let milli = |amount: u128| NearToken::from_millinear(amount);
+        assert_eq!(
+            contract.split_unstake(near(6)),
+            vec![(accounts(0), milli(3500)), (accounts(1), milli(2500))]
+        );
+    }
+
+    #[test]
+    fn test_unstake_turn_partial_failure() {
+        set_context();
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.validators[0].staked = near(5);
+        contract.validators[1].staked = near(5);
+        contract.pool.tickets = near(10);
+        contract.pool.to_unstake = near(4);
+
+        contract.interact_external();
+        assert_eq!(contract.pool.turn_calls, vec![0, 1]);
+        assert_eq!(contract.pool.next_withdraw_turn, 2);
+
+        // The turn only ends once every validator answered
+        contract.unstake_external_callback(accounts(0), near(2), 0, 0, Ok(()));
+        assert!(contract.is_interacting());
+        assert_eq!(contract.next_action, Action::Unstake);
+
+        contract.unstake_external_callback(accounts(1), near(2), 1, 0, Err(PromiseError::Failed));
+
+        // A repeated callback changes nothing
+        contract.unstake_external_callback(accounts(0), near(2), 0, 0, Ok(()));
+        assert!(!contract.is_interacting());
+
+        // Users cannot withdraw until the failed share is unstaked too
+        assert_eq!(contract.next_action, Action::Unstake);
+        assert_eq!(contract.pool.next_withdraw_turn, 1);
+        assert_eq!(contract.pool.to_unstake, near(2));
+        assert_eq!(contract.validators[0].unstaking, near(2));
+        assert_eq!(contract.validators[1].staked, near(5));
+
+        // The retry reopens the same turn and only then moves to withdraw
+        set_context();
+        contract.interact_external();
+        assert_eq!(contract.pool.turn_calls, vec![2]);
+        assert_eq!(contract.pool.next_withdraw_turn, 2);
+
+        contract.unstake_external_callback(accounts(1), near(2), 2, 1, Ok(()));
+        assert_eq!(contract.next_action, Action::Withdraw);
+        assert_eq!(contract.pool.next_withdraw_turn, 2);
+        assert!(contract.pool.to_unstake.is_zero());
+        assert_eq!(contract.validators[1].unstaking, near(2));
+    }
+
+    #[test]
+    fn test_raffled_prize_credits_validators() {
+        set_context();
+        let mut contract = Contract::for_tests();
+        contract.insert_validator(accounts(1), 1);
+
+        let near = |amount: u128| NearToken::from_near(amount);
+        contract.validators[0].staked = near(3);
+        contract.validators[1].staked = near(1);
+
+        // The prize is staked where the rewards came from
+        contract.credit_validators(near(2));
+        assert_eq!(
+            contract.validators[0].staked,
+            NearToken::from_millinear(4500)
+        );
+        assert_eq!(
+            contract.validators[1].staked,
+            NearToken::from_millinear(1500)
+        );
+    }
+
+    fn set_context() {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::*;
+use near_sdk::{near, require, serde_json::json, Gas, Promise, PromiseError};
+
+#[near]
+impl Contract {
+    // Without a whitelist there is nothing to check validators against
+    // Timelocked, returns the id of the proposal
+    pub fn set_whitelist(&mut self, whitelist: Option<AccountId>) -> u64 {
+        self.propose_change(Change::Whitelist(whitelist))
+    }
+
+    pub(crate) fn apply_whitelist(&mut self, whitelist: Option<AccountId>) {
+        if whitelist.is_none() {
+            for validator in self.validators.iter_mut() {
+                validator.verified = true;
+            }
+        }
+
+        self.config.whitelist = whitelist;
+    }
+
+    // Anyone can retry the check of a validator that is still waiting for it
+    pub fn verify_validator(&mut self, account_id: AccountId) -> Promise {
+        let idx = self.validator_index(&account_id);
+        require!(!self.validators[idx].verified, "Validator already verified");
+
+        self.check_whitelist(&account_id)
+    }
+
+    #[private]
+    pub fn verify_validator_callback(
+        &mut self,
+        account_id: AccountId,
+        #[callback_result] call_result: Result<bool, PromiseError>,
+    ) -> bool {
+        let Some(idx) = self.find_validator(&account_id) else {
+            return false;
+        };
+
+        let Ok(whitelisted) = call_result else {
+            log!("Failed to check the whitelist for {}", &account_id);
+            return false;
+        };
+
+        self.validators[idx].verified = whitelisted;
+
+        let event_args = json!({
+            "standard": "nep297",
+            "version": "1.0.0",
+            "event": match whitelisted {
+                true => "validator_verified",
+                false => "validator_rejected",
+            },
+            "data": {
+                "validator": &account_id,
+                "whitelist": &self.config.whitelist,
+            },
+        });
+
+        log!("EVENT_JSON:{}", event_args.to_string());
+        whitelisted
+    }
+
+    // New validators receive no funds until the whitelist confirms them
+    pub(crate) fn add_unverified_validator(&mut self, account_id: AccountId, weight: u32) {
+        let mut validator = Validator::new(account_id.clone(), weight);
+        validator.verified = self.config.whitelist.is_none();
+        self.validators.push(validator);
+
+        if self.config.whitelist.is_some() {
+            // Dropping the promise schedules it, the callback marks the validator
+            self.check_whitelist(&account_id);
+        }
+    }
+
+    pub(crate) fn check_whitelist(&self, account_id: &AccountId) -> Promise {
+        let whitelist = self
+            .config
+            .whitelist
+            .clone()
+            .expect("No whitelist configured");
+
+        Promise::new(whitelist)
+            .function_call(
+                "is_whitelisted".to_string(),
+                json!({ "staking_pool_account_id": account_id })
+                    .to_string()
+                    .into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(10),
+            )
+            .then(Promise::new(env::current_account_id()).function_call(
+                "verify_validator_callback".to_string(),
+                json!({ "account_id": account_id }).to_string().into_bytes(),
+                NO_DEPOSIT,
+                Gas::from_tgas(10),
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::context;
+
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    #[test]
+    #[should_panic(expected = "No validator available")]
+    fn test_unverified_validator_gets_no_deposits() {
+        set_context();
+        let contract = Contract::new(
+            accounts(0),
+            "guardian".parse().unwrap(),
+            U64(0),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("whitelist".parse().unwrap()),
+            None,
+        );
+
+        // Nothing is deposited before the whitelist answers
+        assert!(!contract.validators[0].verified);
+        contract.validator_for_deposit(NearToken::from_near(1));
+    }
+
+    #[test]
+    fn test_verify_validator_callback() {
+        set_context();
+        let mut contract = Contract::new(
+            accounts(0),
+            "guardian".parse().unwrap(),
+            U64(0),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("whitelist".parse().unwrap()),
+            None,
+        );
+        contract.insert_validator(accounts(1), 1);
+
+        assert!(contract.verify_validator_callback(accounts(0), Ok(true)));
+        assert!(!contract.verify_validator_callback(accounts(1), Ok(false)));
+        assert!(!contract.verify_validator_callback(accounts(1), Err(PromiseError::Failed)));
+
+        // Deposits only go to the confirmed validator
+        assert!(contract.validators[0].verified);
+        assert!(!contract.validators[1].verified);
+        assert_eq!(
+            contract.validator_for_deposit(NearToken::from_near(1)),
+            accounts(0)
+        );
+
+        // Removing the whitelist trusts every validator again
+        contract.apply_whitelist(None);
+        assert!(contract.validators[1].verified);
+    }
+
+    fn set_context() {
+        testing_env!(context(&"contract".parse().unwrap()).build());
+    }
+}
@@ -0,0 +1,91 @@
+// Minimal version of a Sputnik DAO, for the integration tests. Proposals are
+// function calls that run as soon as a council member approves them
+use near_sdk::json_types::U64;
+use near_sdk::{env, near, require, AccountId, Gas, NearToken, PanicOnDefault, Promise};
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone, PartialEq)]
+pub enum ProposalStatus {
+    InProgress,
+    Approved,
+}
+
+#[near(serializers=[borsh, json])]
+#[derive(Clone)]
+pub struct Proposal {
+    pub description: String,
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    // JSON arguments of the call
+    pub args: String,
+    pub gas: U64,
+    pub status: ProposalStatus,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    council: Vec<AccountId>,
+    proposals: Vec<Proposal>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new(council: Vec<AccountId>) -> Self {
+        Self {
+            council,
+            proposals: vec![],
+        }
+    }
+
+    pub fn add_proposal(
+        &mut self,
+        description: String,
+        receiver_id: AccountId,
+        method_name: String,
+        args: String,
+        gas: U64,
+    ) -> u64 {
+        self.proposals.push(Proposal {
+            description,
+            receiver_id,
+            method_name,
+            args,
+            gas,
+            status: ProposalStatus::InProgress,
+        });
+
+        self.proposals.len() as u64 - 1
+    }
+
+    pub fn get_last_proposal_id(&self) -> u64 {
+        self.proposals.len() as u64
+    }
+
+    pub fn get_proposal(&self, id: u64) -> Proposal {
+        self.proposals[id as usize].clone()
+    }
+
+    pub fn act_proposal(&mut self, id: u64, action: String) -> Promise {
+        require!(
+            self.council.contains(&env::predecessor_account_id()),
+            "Not a council member"
+        );
+        require!(action == "VoteApprove", "Only approvals are supported");
+
+        let proposal = &mut self.proposals[id as usize];
+        require!(
+            proposal.status == ProposalStatus::InProgress,
+            "Proposal already approved"
+        );
+        proposal.status = ProposalStatus::Approved;
+
+        Promise::new(proposal.receiver_id.clone()).function_call(
+            proposal.method_name.clone(),
+            proposal.args.clone().into_bytes(),
+            NearToken::from_yoctonear(0),
+            Gas::from_gas(proposal.gas.0),
+        )
+    }
+}
@@ -0,0 +1,128 @@
+// Minimal liquid staking contract, for the integration tests. It follows the
+// staking pool interface and adds a swap of the shares for an instant exit
+use near_sdk::json_types::U128;
+use near_sdk::{
+    env, near, require, store::LookupMap, AccountId, NearToken, PanicOnDefault, Promise,
+};
+
+// Fee of the instant exit, in basis points
+const SWAP_FEE_BPS: u128 = 30;
+
+#[near(serializers=[json])]
+pub struct HumanReadableAccount {
+    pub account_id: AccountId,
+    pub unstaked_balance: U128,
+    pub staked_balance: U128,
+    pub can_withdraw: bool,
+}
+
+#[near(serializers=[borsh])]
+#[derive(Default)]
+pub struct Account {
+    shares: u128,
+    unstaked: u128,
+}
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    accounts: LookupMap<AccountId, Account>,
+    total_shares: u128,
+    total_staked: u128,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            accounts: LookupMap::new(b"a"),
+            total_shares: 0,
+            total_staked: 0,
+        }
+    }
+
+    #[payable]
+    pub fn deposit_and_stake(&mut self) {
+        let amount = env::attached_deposit().as_yoctonear();
+        let shares = self.shares_for(amount);
+
+        let account = self.account_mut(&env::predecessor_account_id());
+        account.shares += shares;
+        self.total_shares += shares;
+        self.total_staked += amount;
+    }
+
+    pub fn unstake(&mut self, amount: U128) {
+        let shares = self.burn(&env::predecessor_account_id(), amount.0);
+        require!(shares > 0, "Nothing to unstake");
+        self.account_mut(&env::predecessor_account_id()).unstaked += amount.0;
+    }
+
+    pub fn withdraw_all(&mut self) {
+        let account = self.account_mut(&env::predecessor_account_id());
+        let amount = std::mem::take(&mut account.unstaked);
+        Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(amount));
+    }
+
+    // Swaps the shares worth `amount` for NEAR, paying a fee instead of waiting
+    pub fn instant_unstake(&mut self, amount: U128, min_amount_out: U128) -> U128 {
+        let received = amount.0 - amount.0 * SWAP_FEE_BPS / 10_000;
+        require!(received >= min_amount_out.0, "The swap fee is too high");
+
+        self.burn(&env::predecessor_account_id(), amount.0);
+        Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(received));
+
+        U128(received)
+    }
+
+    // Rewards raise the price of the shares
+    #[payable]
+    pub fn add_rewards(&mut self) {
+        self.total_staked += env::attached_deposit().as_yoctonear();
+    }
+
+    pub fn get_account(&self, account_id: AccountId) -> HumanReadableAccount {
+        let (shares, unstaked) = self
+            .accounts
+            .get(&account_id)
+            .map(|account| (account.shares, account.unstaked))
+            .unwrap_or_default();
+
+        HumanReadableAccount {
+            account_id,
+            unstaked_balance: U128(unstaked),
+            staked_balance: U128(self.value_of(shares)),
+            can_withdraw: true,
+        }
+    }
+
+    fn shares_for(&self, amount: u128) -> u128 {
+        match self.total_staked {
+            0 => amount,
+            total => amount * self.total_shares / total,
+        }
+    }
+
+    fn value_of(&self, shares: u128) -> u128 {
+        match self.total_shares {
+            0 => 0,
+            total => shares * self.total_staked / total,
+        }
+    }
+
+    fn burn(&mut self, account_id: &AccountId, amount: u128) -> u128 {
+        let shares = self.shares_for(amount);
+        let account = self.account_mut(account_id);
+        require!(account.shares >= shares, "Not enough staked");
+
+        account.shares -= shares;
+        self.total_shares -= shares;
+        self.total_staked -= amount;
+        shares
+    }
+
+    fn account_mut(&mut self, account_id: &AccountId) -> &mut Account {
+        self.accounts.entry(account_id.clone()).or_default()
+    }
+}
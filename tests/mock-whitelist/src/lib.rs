@@ -0,0 +1,26 @@
+// Minimal version of the lockup staking pool whitelist, for the integration tests
+use near_sdk::{near, store::LookupSet, AccountId, PanicOnDefault};
+
+#[near(contract_state)]
+#[derive(PanicOnDefault)]
+pub struct Contract {
+    whitelist: LookupSet<AccountId>,
+}
+
+#[near]
+impl Contract {
+    #[init]
+    pub fn new() -> Self {
+        Self {
+            whitelist: LookupSet::new(b"w"),
+        }
+    }
+
+    pub fn is_whitelisted(&self, staking_pool_account_id: AccountId) -> bool {
+        self.whitelist.contains(&staking_pool_account_id)
+    }
+
+    pub fn add_staking_pool(&mut self, staking_pool_account_id: AccountId) -> bool {
+        self.whitelist.insert(staking_pool_account_id)
+    }
+}
@@ -4,8 +4,9 @@ use near_sdk::json_types::U128;
 use near_sdk::NearToken;
 use near_workspaces::network::Sandbox;
 use near_workspaces::{Account, Contract, Worker};
+use poolparty::journal::{CallKind, CallStatus, JournalEntry};
 use poolparty::pool::Pool;
-use poolparty::UserInfo;
+use poolparty::{Action, UserInfo, WithdrawalStatus};
 use serde_json::json;
 
 pub async fn init(
@@ -43,6 +44,7 @@ pub async fn init(
                 "first_raffle": a_minute_from_now.timestamp_millis().to_string(),
                 "time_between_raffles": one_minute.num_milliseconds().to_string(),
                 "min_to_raffle": "0",
+                "timelock_delay": "1",
             }
         ))
         .transact()
@@ -145,7 +147,7 @@ async fn test_deposit() -> Result<(), Box<dyn std::error::Error>> {
 // Emergency -----------------------------------------------------------
 #[tokio::test]
 async fn test_emergency() -> Result<(), Box<dyn std::error::Error>> {
-    let (ana, _bob, _guardian, contract, _sandbox) = init().await?;
+    let (ana, _bob, guardian, contract, _sandbox) = init().await?;
 
     // User can't start or stop emergency
     let user_emergency_start = ana
@@ -206,13 +208,193 @@ async fn test_emergency() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     assert!(withdraw_during_emergency.is_failure());
 
-    // Contract can stop emergency
+    // Only the guardians can stop the emergency
     let contract_emergency_stop = contract
         .call("emergency_stop")
         .args_json(json!({}))
         .transact()
         .await?;
-    assert!(contract_emergency_stop.is_success());
+    assert!(contract_emergency_stop.is_failure());
+
+    let guardian_emergency_stop = guardian
+        .call(contract.id(), "emergency_stop")
+        .args_json(json!({}))
+        .transact()
+        .await?;
+    assert!(guardian_emergency_stop.is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_roles() -> Result<(), Box<dyn std::error::Error>> {
+    let (ana, bob, guardian, contract, _sandbox) = init().await?;
+
+    let grant = contract
+        .call("grant_role")
+        .args_json(json!({"role": "Pauser", "account_id": bob.id()}))
+        .transact()
+        .await?;
+    assert!(grant.is_success());
+
+    // Pausers can stop the pool but not resume it
+    let pause = bob
+        .call(contract.id(), "emergency_start")
+        .transact()
+        .await?;
+    assert!(pause.is_success());
+
+    let resume = bob
+        .call(contract.id(), "emergency_stop")
+        .transact()
+        .await?;
+    assert!(resume.is_failure());
+
+    let resume = guardian
+        .call(contract.id(), "emergency_stop")
+        .transact()
+        .await?;
+    assert!(resume.is_success());
+
+    // Ownership moves once the new owner accepts it
+    let transfer = contract
+        .call("transfer_ownership")
+        .args_json(json!({"new_owner": ana.id()}))
+        .transact()
+        .await?;
+    assert!(transfer.is_success());
+
+    let accept = ana
+        .call(contract.id(), "accept_ownership")
+        .transact()
+        .await?;
+    assert!(accept.is_success());
+
+    let set_fee = contract
+        .call("set_pool_fee")
+        .args_json(json!({"fee": 5}))
+        .transact()
+        .await?;
+    assert!(set_fee.is_failure());
+
+    let set_fee = ana
+        .call(contract.id(), "set_pool_fee")
+        .args_json(json!({"fee": 5}))
+        .transact()
+        .await?;
+    assert!(set_fee.is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_timelock() -> Result<(), Box<dyn std::error::Error>> {
+    let (_ana, _bob, guardian, contract, _sandbox) = init().await?;
+
+    let delay = contract
+        .call("update_config")
+        .args_json(json!({"patch": {"timelock_delay": "86400000"}}))
+        .transact()
+        .await?;
+    assert!(delay.is_success());
+
+    // Sensitive changes wait for the delay, and the guardian can cancel them
+    let propose = contract
+        .call("set_pool_fee")
+        .args_json(json!({"fee": 50}))
+        .transact()
+        .await?;
+    let id = propose.json::<u64>()?;
+
+    let execute = contract
+        .call("execute_proposal")
+        .args_json(json!({"id": id}))
+        .transact()
+        .await?;
+    assert!(execute.is_failure());
+
+    let proposals = contract
+        .view("get_proposals")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(proposals[0]["change"]["Config"]["pool_fee"], 50);
+
+    let cancel = guardian
+        .call(contract.id(), "cancel_proposal")
+        .args_json(json!({"id": id}))
+        .transact()
+        .await?;
+    assert!(cancel.is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_guardians() -> Result<(), Box<dyn std::error::Error>> {
+    let (ana, bob, guardian, contract, _sandbox) = init().await?;
+
+    let propose = contract
+        .call("propose_change")
+        .args_json(json!({"change": {"Guardians": {
+            "members": [guardian.id(), ana.id(), bob.id()],
+            "threshold": 2,
+            "approval_window": "3600000",
+        }}}))
+        .transact()
+        .await?;
+    let execute = contract
+        .call("execute_proposal")
+        .args_json(json!({"id": propose.json::<u64>()?}))
+        .transact()
+        .await?;
+    assert!(execute.is_success());
+
+    let pause = contract.call("emergency_start").transact().await?;
+    assert!(pause.is_success());
+
+    // One approval is not enough to resume the pool
+    let resume = guardian
+        .call(contract.id(), "emergency_stop")
+        .transact()
+        .await?;
+    let id = resume.json::<u64>()?;
+
+    let config = contract
+        .view("get_config")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(config["paused"]["deposit"], true);
+
+    let actions = contract
+        .view("get_pending_actions")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(actions[0]["action"]["Resume"][0], "Deposit");
+
+    let approve = bob
+        .call(contract.id(), "approve_action")
+        .args_json(json!({"id": id}))
+        .transact()
+        .await?;
+    assert!(approve.is_success());
+    assert!(approve
+        .logs()
+        .iter()
+        .any(|log| log.contains("action_executed")));
+
+    let config = contract
+        .view("get_config")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(config["paused"]["deposit"], false);
+
+    // The reserve leaves only through the guardians
+    let unstake = guardian
+        .call(contract.id(), "unstake")
+        .args_json(json!({"amount": NearToken::from_near(1)}))
+        .transact()
+        .await?;
+    assert!(unstake.is_failure());
 
     Ok(())
 }
@@ -229,6 +411,11 @@ async fn eucliden_div() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[tokio::test]
+#[allow(
+    clippy::needless_range_loop,
+    clippy::manual_range_contains,
+    clippy::useless_vec
+)]
 async fn test_random_u128() -> Result<(), Box<dyn std::error::Error>> {
     let (_ana, _bob, _guardian, contract, _sandbox) = init().await?;
     println!("Running test_random_u128, which may take a while... please wait!");
@@ -338,6 +525,56 @@ async fn test_raffle() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_ping_before_prize() -> Result<(), Box<dyn std::error::Error>> {
+    let (ana, _bob, _guardian, contract, sandbox) = init().await?;
+
+    let _ana_deposit = ana
+        .call(contract.id(), "deposit_and_stake")
+        .deposit(NearToken::from_near(50))
+        .max_gas()
+        .transact()
+        .await?;
+
+    let set_ping = contract
+        .call("set_ping_before_prize")
+        .args_json(json!({"enabled": true}))
+        .transact()
+        .await?;
+    assert!(set_ping.is_success());
+
+    sandbox.fast_forward(200).await?;
+
+    let prize_update_outcome = ana
+        .call(contract.id(), "update_prize")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(prize_update_outcome.is_success());
+
+    let journal = contract
+        .view("get_journal")
+        .args_json(json!({"from": 0, "until": 500}))
+        .await?
+        .json::<Vec<JournalEntry>>()?;
+
+    let ping = journal
+        .iter()
+        .rfind(|entry| matches!(entry.kind, CallKind::Ping))
+        .unwrap();
+    let query = journal
+        .iter()
+        .rfind(|entry| matches!(entry.kind, CallKind::Query))
+        .unwrap();
+
+    // The mock validator has no `ping`, the balance is still queried after it
+    assert!(matches!(ping.status, CallStatus::Failed));
+    assert!(matches!(query.status, CallStatus::Succeeded));
+    assert!(ping.completed_at.unwrap() <= query.completed_at.unwrap());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
     let (ana, bob, _guardian, contract, sandbox) = init().await?;
@@ -375,7 +612,8 @@ async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
         NearToken::from_near(40).as_yoctonear()
     );
     assert_eq!(ana_balance.unstaked, NearToken::from_near(10));
-    assert_eq!(ana_balance.withdraw_turn, 1);
+    // Waits for the next unstake turn and then epochs_wait
+    assert_eq!(ana_balance.withdraw_turn, 4);
 
     let pool_info = contract.view("get_pool_info").await?.json::<Pool>()?;
     assert_eq!(
@@ -398,6 +636,20 @@ async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
         NearToken::from_near(42).as_yoctonear()
     );
 
+    let ana_status = contract
+        .view("get_withdrawal_status")
+        .args_json(json!({"user": ana.id()}))
+        .await?
+        .json::<WithdrawalStatus>()?;
+    assert_eq!(ana_status.unstaked, NearToken::from_near(10));
+    assert_eq!(ana_status.withdraw_turn, Some(1));
+    assert_eq!(ana_status.next_action, Action::Withdraw);
+    assert!(!ana_status.available);
+    assert_eq!(
+        ana_status.epochs_remaining,
+        ana_status.next_withdraw_epoch - ana_status.current_epoch
+    );
+
     let _bob_unstake = bob
         .call(contract.id(), "unstake")
         .args_json(json!({"amount": NearToken::from_near(1)}))
@@ -410,7 +662,8 @@ async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
         .args_json(json!({"user": bob.id()}))
         .await?
         .json::<UserInfo>()?;
-    assert_eq!(bob_details.withdraw_turn, 2);
+    // Bob joins the unstake turn after the ongoing withdraw
+    assert_eq!(bob_details.withdraw_turn, ana_status.epochs_remaining + 4);
 
     let ana_prev = ana.view_account().await?;
     //  Ana doesnt wait the 1 epoch
@@ -423,6 +676,14 @@ async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
 
     sandbox.fast_forward(500).await?;
 
+    // The funds come back from the external pool
+    let interact_external = contract
+        .call("interact_external")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(interact_external.is_success());
+
     // Ana waits the 1 epoch
     let ana_withdraw = ana
         .call(contract.id(), "withdraw_all")
@@ -442,7 +703,7 @@ async fn test_unstake_and_withdraw() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     let pool_info = contract.view("get_pool_info").await?.json::<Pool>()?;
-    assert_eq!(pool_info.next_withdraw_turn, 2);
+    assert_eq!(pool_info.next_withdraw_turn, 3);
 
     Ok(())
 }
@@ -561,7 +822,13 @@ async fn test_full_flow() -> Result<(), Box<dyn std::error::Error>> {
         .args_json(json!({"user": charlie.id()}))
         .await?
         .json::<UserInfo>()?;
-    assert_eq!(charlie_info.withdraw_turn, 2);
+    let charlie_status = contract
+        .view("get_withdrawal_status")
+        .args_json(json!({"user": charlie.id()}))
+        .await?
+        .json::<WithdrawalStatus>()?;
+    assert!(!charlie_info.available);
+    assert_eq!(charlie_info.withdraw_turn, charlie_status.epochs_remaining);
 
     let _dana_deposit = dana
         .call(contract.id(), "deposit_and_stake")
@@ -636,6 +903,278 @@ async fn test_full_flow() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Whitelist --------------------------------------------------------
+#[tokio::test]
+async fn test_whitelist() -> Result<(), Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let contract_wasm = near_workspaces::compile_project("./").await?;
+    let contract = sandbox.dev_deploy(&contract_wasm).await?;
+
+    let whitelist_wasm = near_workspaces::compile_project("./tests/mock-whitelist").await?;
+    let whitelist = sandbox.dev_deploy(&whitelist_wasm).await?;
+    let init = whitelist.call("new").transact().await?;
+    assert!(init.is_success());
+
+    let staking_contract = sandbox
+        .dev_deploy(&std::fs::read("./tests/mock-validator/validator.wasm")?)
+        .await?;
+
+    let init = contract
+        .call("new")
+        .args_json(json!(
+            {
+                "guardian": contract.id(),
+                "external_pool": staking_contract.id(),
+                "first_raffle": "0",
+                "whitelist": whitelist.id(),
+            }
+        ))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(init.is_success());
+
+    // The validator is not whitelisted, no funds go to it
+    let validators = contract
+        .view("get_validators")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(validators[0]["verified"], false);
+
+    let deposit = contract
+        .call("deposit_and_stake")
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(deposit.is_failure());
+
+    let add = whitelist
+        .call("add_staking_pool")
+        .args_json(json!({"staking_pool_account_id": staking_contract.id()}))
+        .transact()
+        .await?;
+    assert!(add.is_success());
+
+    let verify = contract
+        .call("verify_validator")
+        .args_json(json!({"account_id": staking_contract.id()}))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(verify.json::<bool>()?);
+
+    let deposit = contract
+        .call("deposit_and_stake")
+        .deposit(NearToken::from_near(1))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(deposit.is_success());
+
+    Ok(())
+}
+
+// Liquid staking -------------------------------------------------
+#[tokio::test]
+async fn test_instant_exit() -> Result<(), Box<dyn std::error::Error>> {
+    let sandbox = near_workspaces::sandbox().await?;
+
+    let contract_wasm = near_workspaces::compile_project("./").await?;
+    let contract = sandbox.dev_deploy(&contract_wasm).await?;
+
+    let liquid_wasm = near_workspaces::compile_project("./tests/mock-liquid-staking").await?;
+    let liquid = sandbox.dev_deploy(&liquid_wasm).await?;
+    let init = liquid.call("new").transact().await?;
+    assert!(init.is_success());
+
+    let init = contract
+        .call("new")
+        .args_json(json!(
+            {
+                "guardian": contract.id(),
+                "external_pool": liquid.id(),
+                "first_raffle": "0",
+                "timelock_delay": "1",
+            }
+        ))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(init.is_success());
+
+    // The test pool waits a single millisecond for its proposals
+    let set_liquid = contract
+        .call("set_liquid_validator")
+        .args_json(json!({"account_id": liquid.id()}))
+        .transact()
+        .await?;
+    let execute = contract
+        .call("execute_proposal")
+        .args_json(json!({"id": set_liquid.json::<u64>()?}))
+        .transact()
+        .await?;
+    assert!(execute.is_success());
+
+    let ana = sandbox.dev_create_account().await?;
+    let deposit = ana
+        .call(contract.id(), "deposit_and_stake")
+        .deposit(NearToken::from_near(5))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(deposit.is_success());
+
+    // A fee above the accepted one fails and keeps the tickets
+    let exit = ana
+        .call(contract.id(), "instant_exit")
+        .args_json(json!({"amount": NearToken::from_near(2), "max_fee_bps": 10}))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(!exit.json::<bool>()?);
+
+    let balance_before = ana.view_account().await?.balance;
+    let exit = ana
+        .call(contract.id(), "instant_exit")
+        .args_json(json!({"amount": NearToken::from_near(2), "max_fee_bps": 50}))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(exit.json::<bool>()?);
+
+    let ana_info = contract
+        .view("get_user_info")
+        .args_json(json!({"user": ana.id()}))
+        .await?
+        .json::<UserInfo>()?;
+    assert_eq!(ana_info.staked, NearToken::from_near(3));
+
+    // The swap fee is the only cost, no waiting for the unbonding period
+    let balance_after = ana.view_account().await?.balance;
+    assert!(balance_after > balance_before.saturating_add(NearToken::from_millinear(1990)));
+
+    // Rewards of the liquid staking are valued through its share price
+    let rewards = contract
+        .as_account()
+        .call(liquid.id(), "add_rewards")
+        .deposit(NearToken::from_near(1))
+        .transact()
+        .await?;
+    assert!(rewards.is_success());
+
+    let update = contract
+        .call("update_prize")
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(update.is_success());
+
+    let prize = contract.view("get_pool_info").await?.json::<Pool>()?.prize;
+    assert!(prize > NearToken::from_near(0));
+
+    Ok(())
+}
+
+// DAO ------------------------------------------------------------
+#[tokio::test]
+async fn test_dao_governance() -> Result<(), Box<dyn std::error::Error>> {
+    let (ana, _bob, _guardian, contract, sandbox) = init().await?;
+
+    let dao_wasm = near_workspaces::compile_project("./tests/mock-dao").await?;
+    let dao = sandbox.dev_deploy(&dao_wasm).await?;
+    let init = dao
+        .call("new")
+        .args_json(json!({"council": [ana.id()]}))
+        .transact()
+        .await?;
+    assert!(init.is_success());
+
+    // Hand the fee to the DAO
+    let propose = contract
+        .call("propose_change")
+        .args_json(json!({"change": {"Dao": dao.id()}}))
+        .transact()
+        .await?;
+    let execute = contract
+        .call("execute_proposal")
+        .args_json(json!({"id": propose.json::<u64>()?}))
+        .transact()
+        .await?;
+    assert!(execute.is_success());
+
+    let grant = contract
+        .call("grant_role")
+        .args_json(json!({"role": "FeeManager", "account_id": dao.id()}))
+        .transact()
+        .await?;
+    assert!(grant.is_success());
+
+    // Only the DAO can call dao_execute
+    let direct = ana
+        .call(contract.id(), "dao_execute")
+        .args_json(json!({"proposal_id": 0, "change": {"Config": {"pool_fee": 7}}}))
+        .transact()
+        .await?;
+    assert!(direct.is_failure());
+
+    let args = json!({"proposal_id": 0, "change": {"Config": {"pool_fee": 7}}});
+    let add = ana
+        .call(dao.id(), "add_proposal")
+        .args_json(json!({
+            "description": "Lower the pool fee",
+            "receiver_id": contract.id(),
+            "method_name": "dao_execute",
+            "args": args.to_string(),
+            "gas": "50000000000000",
+        }))
+        .transact()
+        .await?;
+    let proposal_id = add.json::<u64>()?;
+    assert_eq!(proposal_id, 0);
+
+    let approve = ana
+        .call(dao.id(), "act_proposal")
+        .args_json(json!({"id": proposal_id, "action": "VoteApprove"}))
+        .max_gas()
+        .transact()
+        .await?;
+    assert!(approve.is_success());
+
+    // The fee waits in the timelock like any other proposal
+    let proposals = contract
+        .view("get_proposals")
+        .await?
+        .json::<serde_json::Value>()?;
+    assert_eq!(proposals[0]["change"]["Config"]["pool_fee"], 7);
+
+    let execute = contract
+        .call("execute_proposal")
+        .args_json(json!({"id": proposals[0]["id"]}))
+        .transact()
+        .await?;
+    assert!(execute.is_success());
+
+    let pool = contract.view("get_pool_info").await?.json::<Pool>()?;
+    assert_eq!(pool.pool_fee, 7);
+
+    // The event names the DAO proposal
+    assert!(approve
+        .logs()
+        .iter()
+        .any(|log| log.contains("dao_proposal_executed") && log.contains("\"proposal_id\":0")));
+
+    let executed = contract
+        .view("is_dao_proposal_executed")
+        .args_json(json!({"proposal_id": proposal_id}))
+        .await?
+        .json::<bool>()?;
+    assert!(executed);
+
+    Ok(())
+}
+
 // Helpers --------------------------------------------------------
 fn roundup_balance(amount: NearToken) -> u128 {
     let rem = amount.as_yoctonear() % 10u128.pow(24);